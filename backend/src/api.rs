@@ -0,0 +1,3607 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Cursor,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use axum::{
+    body::Bytes,
+    error_handling::HandleErrorLayer,
+    extract::{
+        rejection::JsonRejection, ConnectInfo, DefaultBodyLimit, Path, Query, RawPathParams,
+        RawQuery, Request, State,
+    },
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{delete, get, post, put},
+    Extension, Json, Router,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::StreamExt;
+use governor::{
+    clock::{Clock, DefaultClock},
+    middleware::NoOpMiddleware,
+    DefaultKeyedRateLimiter, Quota,
+};
+use image::{ImageFormat, Luma};
+use ipnet::IpNet;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use qrcode::{render::svg, QrCode};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use tower::{
+    limit::GlobalConcurrencyLimitLayer, load_shed::error::Overloaded, BoxError, ServiceBuilder,
+    ServiceExt,
+};
+use tower_governor::{
+    governor::GovernorConfigBuilder, key_extractor::KeyExtractor, GovernorError, GovernorLayer,
+};
+use tower_http::{
+    compression::CompressionLayer,
+    cors::{AllowOrigin, CorsLayer},
+    normalize_path::NormalizePath,
+    timeout::TimeoutLayer,
+};
+use tracing::{info, info_span, warn, Instrument};
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme},
+    IntoParams, OpenApi, ToSchema,
+};
+
+use crate::destinations::{DestinationCheck, DestinationVerifier};
+use crate::persistence::{
+    AliasOutcome, BackupStatus, BackupSummary, BulkMode, ClickBucket, Link, LinkFilter, LinkPatch,
+    NamespaceSummary, Persistence, RenameOutcome, WriteOutcome, WILDCARD_PLACEHOLDER,
+    WILDCARD_SUFFIX,
+};
+use crate::slugs::SlugGenerator;
+
+pub type ServerState = Arc<Persistence>;
+
+/// Bounds on what any one client can make the server do.
+pub struct Limits {
+    pub read_rate: RateLimitLayer,
+    pub write_rate: RateLimitLayer,
+    /// On top of `write_rate`, for writes to a namespace.
+    pub namespace_write_rate: Option<NamespaceRateLimiter>,
+    /// The largest request body accepted by most endpoints.
+    pub max_body_bytes: usize,
+    /// The largest request body accepted by endpoints that take many items at once.
+    pub max_batch_body_bytes: usize,
+    /// How long a request may take, including receiving its body, before it gets a 408.
+    pub request_timeout: Duration,
+    /// How many requests may be in flight at once. Beyond this they're turned away with a 503
+    /// rather than queueing up behind the db lock.
+    pub max_concurrent_requests: Option<usize>,
+}
+
+/// Knobs for how requests are served, as opposed to how many of them are.
+#[derive(Debug)]
+pub struct ApiConfig {
+    /// Log redirect destinations without their query strings, which may carry secrets.
+    pub redact_logged_queries: bool,
+    /// Where to send redirects for unknown short_forms, as `{url}?ns=...&slug=...`. Without one,
+    /// they get a "no link" response instead.
+    pub fallback_url: Option<String>,
+    /// Overrides `fallback_url` for particular namespaces.
+    pub namespace_fallback_urls: HashMap<String, String>,
+    /// How long a create's `Idempotency-Key` is remembered, so that a retry gets the original
+    /// response rather than a 409.
+    pub idempotency_window: Duration,
+    /// The longest short_form that can be created, in bytes.
+    pub max_short_form_len: usize,
+    /// If set, a short_form chosen by a client (rather than generated) must match this in full.
+    /// Build it with `short_form_pattern`, which anchors it.
+    pub short_form_pattern: Option<Regex>,
+    /// The longest long_form that can be stored, in bytes. Every byte is in every backup.
+    pub max_long_form_len: usize,
+    /// Browser origins (like `https://admin.example.com`) allowed to call the `/v1` API. Empty
+    /// means no cross-origin access.
+    pub allowed_origins: Vec<HeaderValue>,
+    /// A shared credential required for everything but redirects.
+    pub basic_auth: Option<BasicAuth>,
+    /// The credential for `/v1/admin/*`, which can force store uploads and the like. Without one,
+    /// those routes aren't served at all. It's needed instead of `basic_auth`, not as well.
+    pub admin_auth: Option<BasicAuth>,
+    /// Proxies (like a load balancer) whose `X-Forwarded-For` is believed when logging who a
+    /// request came from. The rate limiters take their own copy.
+    pub trusted_proxies: Vec<IpNet>,
+    /// If non-empty, long_forms must point at one of these hosts. `*.example.com` matches any
+    /// subdomain of example.com (but not example.com itself).
+    pub allowed_hosts: Vec<String>,
+    /// long_forms can't point at these hosts, even allowed ones. Patterns as in `allowed_hosts`.
+    pub blocked_hosts: Vec<String>,
+    /// If non-empty, the only namespaces that exist: requests for any other get a 404, so that a
+    /// typo can't start a new namespace.
+    pub allowed_namespaces: HashSet<String>,
+    /// Where every route is mounted, like `/shortener`. Empty means the root.
+    pub route_prefix: String,
+    /// The externally visible URL that `route_prefix` is served at, like
+    /// `https://go.example.com/shortener`, for the URLs in responses. Without one, URLs are
+    /// relative where possible, and otherwise built from the request's Host.
+    pub public_base_url: Option<String>,
+    /// Names the links created without a short_form.
+    pub slugs: SlugGenerator,
+    /// Count `HEAD` redirects as clicks. They're mostly link checkers and uptime monitors, not
+    /// people.
+    pub count_head_clicks: bool,
+    /// Let creates set `created_at`, and allow imports, which keep their links' timestamps. Off by
+    /// default, since both let clients backdate links.
+    pub allow_created_at: bool,
+    /// Where `GET /` redirects, like a landing page. Without one, it describes the service.
+    pub root_redirect: Option<String>,
+    /// Route `/v1/redirect/ns/foo/` (and every other path with trailing slashes) as though it
+    /// were `/v1/redirect/ns/foo`. short_forms ending in `/` can't be created, since they'd be
+    /// unreachable.
+    pub trim_trailing_slashes: bool,
+    /// If set, creates check that the long_form answers before making the link, and say what it
+    /// answered. Off by default: it's an outbound request to wherever a client points it.
+    pub verify_destinations: Option<DestinationVerifier>,
+}
+
+#[derive(Clone)]
+pub struct BasicAuth {
+    pub user: String,
+    pub password: String,
+}
+impl BasicAuth {
+    /// What a client sends after `Basic ` in its Authorization header.
+    fn token(&self) -> String {
+        BASE64_STANDARD.encode(format!("{}:{}", self.user, self.password))
+    }
+}
+impl FromStr for BasicAuth {
+    type Err = String;
+
+    /// Parses `user:pass`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, password) = s
+            .split_once(':')
+            .ok_or_else(|| "expected USER:PASSWORD".to_owned())?;
+        Ok(Self {
+            user: user.to_owned(),
+            password: password.to_owned(),
+        })
+    }
+}
+// Keeps the password out of logs and panics.
+impl std::fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            redact_logged_queries: false,
+            fallback_url: None,
+            namespace_fallback_urls: HashMap::new(),
+            idempotency_window: Duration::from_secs(60 * 60),
+            max_short_form_len: 128,
+            short_form_pattern: None,
+            max_long_form_len: 2048,
+            allowed_origins: Vec::new(),
+            basic_auth: None,
+            admin_auth: None,
+            trusted_proxies: Vec::new(),
+            allowed_hosts: Vec::new(),
+            blocked_hosts: Vec::new(),
+            allowed_namespaces: HashSet::new(),
+            route_prefix: String::new(),
+            public_base_url: None,
+            slugs: SlugGenerator::default(),
+            count_head_clicks: false,
+            allow_created_at: false,
+            root_redirect: None,
+            trim_trailing_slashes: true,
+            verify_destinations: None,
+        }
+    }
+}
+
+impl ApiConfig {
+    /// Where an unknown `namespace/short_form` should redirect to, if anywhere.
+    fn fallback_redirect(&self, namespace: &str, short_form: &str) -> Option<String> {
+        let base = self
+            .namespace_fallback_urls
+            .get(namespace)
+            .or(self.fallback_url.as_ref())?;
+        let query = serde_urlencoded::to_string([("ns", namespace), ("slug", short_form)])
+            .expect("strings always urlencode");
+        let separator = if base.contains('?') { '&' } else { '?' };
+        Some(format!("{base}{separator}{query}"))
+    }
+
+    /// The absolute URL of `path` (which is relative to `route_prefix`), as `headers`' sender sees it.
+    fn absolute_url(&self, headers: &HeaderMap, path: &str) -> String {
+        match &self.public_base_url {
+            Some(base) => format!("{}{path}", base.trim_end_matches('/')),
+            None => format!("{}{}{path}", request_origin(headers), self.route_prefix),
+        }
+    }
+
+    /// A URL for `path` (which is relative to `route_prefix`), for a `Location` header.
+    fn location(&self, path: &str) -> String {
+        match &self.public_base_url {
+            Some(base) => format!("{}{path}", base.trim_end_matches('/')),
+            None => format!("{}{path}", self.route_prefix),
+        }
+    }
+
+    /// The public URL that redirects to `short_form`'s destination.
+    fn short_url(&self, headers: &HeaderMap, namespace: &str, short_form: &str) -> String {
+        let path = format!(
+            "/v1/redirect/{}/{}",
+            escape_path(namespace),
+            escape_path(short_form)
+        );
+        self.absolute_url(headers, &path)
+    }
+
+    fn check_short_form(&self, short_form: &str) -> AppResult<()> {
+        check_len("short_form", short_form, self.max_short_form_len)?;
+        if self.trim_trailing_slashes && short_form.ends_with('/') {
+            return Err(AppError::new(
+                ErrorCode::Validation,
+                anyhow!("short_form {short_form:?} can't end in a slash"),
+            ));
+        }
+        if COLLECTION_ROUTES.contains(&short_form) {
+            return Err(AppError::new(
+                ErrorCode::Validation,
+                anyhow!(
+                    "short_form {short_form:?} is taken by /v1/links/{{namespace}}/{short_form}"
+                ),
+            ));
+        }
+        if let Some((_, suffix)) = short_form.rsplit_once('/') {
+            if SUB_RESOURCES.contains(&suffix) {
+                return Err(AppError::new(
+                    ErrorCode::Validation,
+                    anyhow!("short_form {short_form:?} can't end in /{suffix}, a link's own route"),
+                ));
+            }
+        }
+        match &self.short_form_pattern {
+            Some(pattern) if !pattern.is_match(short_form) => Err(AppError::new(
+                ErrorCode::Validation,
+                anyhow!("short_form {short_form:?} doesn't match {pattern}"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The namespace and short_form `long_form` redirects through, if it's one of this service's
+    /// own short URLs. Those can only be recognized with a `public_base_url`.
+    fn own_redirect(&self, long_form: &str) -> Option<(String, String)> {
+        let base = url::Url::parse(self.public_base_url.as_deref()?).ok()?;
+        let url = url::Url::parse(long_form).ok()?;
+        if url.origin() != base.origin() {
+            return None;
+        }
+        let rest = url
+            .path()
+            .strip_prefix(base.path().trim_end_matches('/'))?
+            .strip_prefix("/v1/redirect/")?;
+        let rest = match self.trim_trailing_slashes {
+            true => rest.trim_end_matches('/'),
+            false => rest,
+        };
+        let (namespace, short_form) = rest.split_once('/').unwrap_or((rest, HOME_SHORT_FORM));
+        let decode = |s| percent_encoding::percent_decode_str(s).decode_utf8().ok();
+        Some((
+            decode(namespace)?.into_owned(),
+            decode(short_form)?.into_owned(),
+        ))
+    }
+
+    /// Refuses a link whose long_form is its own short URL, which would redirect forever.
+    fn check_not_own_short_url(
+        &self,
+        namespace: &str,
+        short_form: &str,
+        long_form: &str,
+    ) -> AppResult<()> {
+        match self.own_redirect(long_form) {
+            Some((ns, sf)) if ns == namespace && sf == short_form => {
+                Err(redirect_loop(namespace, short_form))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_long_form(&self, long_form: &str) -> AppResult<()> {
+        check_len("long_form", long_form, self.max_long_form_len)?;
+        let bad_request = |err| AppError::new(ErrorCode::Validation, err);
+        // Anything else would redirect relative to wherever the link was clicked.
+        let url = url::Url::parse(long_form)
+            .map_err(|err| bad_request(anyhow!("long_form isn't an absolute URL: {err}")))?;
+        if self.allowed_hosts.is_empty() && self.blocked_hosts.is_empty() {
+            return Ok(());
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| bad_request(anyhow!("long_form has no host")))?;
+        let matches = |pattern: &String| host_matches(pattern, host);
+        if self.blocked_hosts.iter().any(matches) {
+            return Err(bad_request(anyhow!("links to {host} are blocked")));
+        }
+        if !self.allowed_hosts.is_empty() && !self.allowed_hosts.iter().any(matches) {
+            return Err(bad_request(anyhow!("links to {host} aren't allowed")));
+        }
+        Ok(())
+    }
+}
+
+/// A wildcard link has to say where the part of the short_form it matches goes.
+fn check_wildcard(short_form: &str, long_form: &str) -> AppResult<()> {
+    if short_form.ends_with(WILDCARD_SUFFIX) && !long_form.contains(WILDCARD_PLACEHOLDER) {
+        return Err(AppError::new(
+            ErrorCode::Validation,
+            anyhow!(
+                "short_form {short_form:?} is a wildcard, so long_form needs a {WILDCARD_PLACEHOLDER} for what it matches"
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Everything but RFC 3986's unreserved characters, so a segment can't add path segments, a query,
+// or a fragment of its own.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// The link for `short_form`, or failing that the wildcard link covering it with its long_form
+/// filled in. An exact match always wins over a wildcard.
+fn lookup_link(
+    state: &ServerState,
+    namespace: &str,
+    short_form: &str,
+) -> anyhow::Result<Option<Link>> {
+    if let Some(link) = state.get_link_cached(namespace.to_owned(), short_form.to_owned())? {
+        return Ok(Some(link));
+    }
+    let Some((mut link, matched)) =
+        state.get_wildcard_link(namespace.to_owned(), short_form.to_owned())?
+    else {
+        return Ok(None);
+    };
+    link.long_form = link
+        .long_form
+        .replace(WILDCARD_PLACEHOLDER, &escape_path(&matched));
+    Ok(Some(link))
+}
+
+/// Escapes `path` for a URL. Slashes stay path separators; each segment between them is escaped,
+/// so that spaces, `?`, `#` and the like come back out of the URL as they went in.
+fn escape_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn redirect_loop(namespace: &str, short_form: &str) -> AppError {
+    AppError::new(
+        ErrorCode::Validation,
+        anyhow!("long_form redirects back to {namespace}/{short_form}, which would loop forever"),
+    )
+}
+
+// How far `check_redirect_loop` follows this service's own short URLs before giving up.
+const MAX_REDIRECT_HOPS: usize = 8;
+/// Like `ApiConfig::check_not_own_short_url`, but also refuses a long_form that's another short
+/// URL leading (through however many more) back to this link.
+fn check_redirect_loop(
+    state: &ServerState,
+    cfg: &ApiConfig,
+    namespace: &str,
+    short_form: &str,
+    long_form: &str,
+) -> AppResult<()> {
+    let mut next = long_form.to_owned();
+    for _ in 0..MAX_REDIRECT_HOPS {
+        let Some((ns, sf)) = cfg.own_redirect(&next) else {
+            return Ok(());
+        };
+        if ns == namespace && sf == short_form {
+            return Err(redirect_loop(namespace, short_form));
+        }
+        let Some(link) = state.get_link(ns, sf)? else {
+            return Ok(());
+        };
+        next = link.long_form;
+    }
+    Ok(())
+}
+
+/// Compiles `pattern` for `ApiConfig::short_form_pattern`, so that it has to match a whole
+/// short_form rather than just some part of it.
+pub fn short_form_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{pattern})$"))
+}
+
+/// Whether `host` is `pattern`, or, for a `*.example.com` pattern, a subdomain of example.com.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.len().checked_sub(domain.len() + 1).is_some_and(|dot| {
+            host.as_bytes()[dot] == b'.' && host[dot + 1..].eq_ignore_ascii_case(domain)
+        }),
+        None => host.eq_ignore_ascii_case(pattern),
+    }
+}
+
+fn check_len(field: &str, value: &str, max: usize) -> AppResult<()> {
+    if value.is_empty() {
+        return Err(AppError::new(
+            ErrorCode::Validation,
+            anyhow!("{field} can't be empty"),
+        ));
+    }
+    if value.len() > max {
+        return Err(AppError::new(
+            ErrorCode::Validation,
+            anyhow!(
+                "{field} is {} bytes, more than the {max} allowed",
+                value.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct RootResponse {
+    service: &'static str,
+    /// The running build's version, to confirm what's deployed.
+    version: &'static str,
+    docs: String,
+}
+async fn root(Extension(cfg): Extension<Arc<ApiConfig>>) -> Response {
+    match &cfg.root_redirect {
+        Some(url) => Redirect::temporary(url).into_response(),
+        None => Json(RootResponse {
+            service: "flylinks",
+            version: env!("CARGO_PKG_VERSION"),
+            docs: cfg.location("/docs"),
+        })
+        .into_response(),
+    }
+}
+
+pub fn router(state: ServerState, limits: Limits, cfg: ApiConfig) -> Router {
+    // It's important that `*short_form` is a wildcard capture so that we support keys with slashes in them
+    // Redirects and lookups are far more frequent than writes, so they get a separate (higher) limit.
+    // `get` answers HEAD too, with the same status and headers but no body.
+    let mut redirects = Router::new()
+        .route("/v1/redirect/:namespace/*short_form+", get(redirect_link))
+        .route("/v1/redirect/:namespace", get(redirect_home))
+        .route("/v1/redirect/:namespace/", get(redirect_home))
+        .route("/v1/resolve/:namespace/*short_form", get(resolve_link))
+        .layer(limits.read_rate.clone());
+    let reads = Router::new()
+        .route("/v1/namespaces", get(list_namespaces))
+        // Outside `/v1/links/`, where it would shadow a namespace of the same name.
+        .route("/v1/short-forms/*short_form", get(links_by_short_form))
+        .route("/v1/links/:namespace", get(list_links))
+        .route("/v1/links/:namespace/bookmarks.html", get(export_bookmarks))
+        .route("/v1/links/:namespace/search", get(search_links))
+        .route("/v1/links/:namespace/map", get(link_map))
+        .route("/v1/links/:namespace/export", get(export_links))
+        .route("/v1/links/:namespace/random", get(random_link))
+        .route("/v1/links/:namespace/validate", post(validate_link))
+        .route("/v1/links/:namespace/*short_form", get(get_link_resource))
+        .route("/v1/reverse_lookup/:namespace", get(reverse_lookup_query))
+        .route("/v1/reverse_lookup/:namespace", post(reverse_lookup))
+        .route(
+            "/v1/reverse_lookup/:namespace/batch",
+            post(reverse_lookup_batch).layer(DefaultBodyLimit::max(limits.max_batch_body_bytes)),
+        )
+        .route("/v1/home/:namespace", get(get_home))
+        .layer(limits.read_rate.clone());
+    let writes = Router::new()
+        .route("/v1/links/:namespace", post(create_link))
+        .route("/v1/home/:namespace", put(set_home))
+        .route(
+            "/v1/bulk/:namespace",
+            post(bulk_create_links).layer(DefaultBodyLimit::max(limits.max_batch_body_bytes)),
+        )
+        .route(
+            "/v1/links/:namespace/import",
+            post(import_links).layer(DefaultBodyLimit::max(limits.max_batch_body_bytes)),
+        )
+        .route(
+            "/v1/links/:namespace/delete",
+            post(bulk_delete_links).layer(DefaultBodyLimit::max(limits.max_batch_body_bytes)),
+        )
+        .route(
+            "/v1/links/:namespace/*short_form",
+            put(update_link)
+                .patch(patch_link)
+                .post(post_link_resource)
+                .delete(delete_link),
+        )
+        .layer(limits.write_rate.clone());
+    // A route layer, to see the matched `:namespace`. It's checked after the per-IP limit, so
+    // that a client over its own limit can't use up the namespace's too.
+    let writes = match limits.namespace_write_rate {
+        Some(limiter) => {
+            writes.route_layer(middleware::from_fn_with_state(limiter, limit_namespace))
+        }
+        None => writes,
+    };
+    // Kept apart from the rest, since they take their own credential.
+    let admin_reads = Router::new()
+        .route("/v1/admin/backup/status", get(backup_status))
+        // Not with the writes, since replicas can reload too.
+        .route("/v1/admin/reload", post(reload))
+        .layer(limits.read_rate);
+    let admin_writes = Router::new()
+        .route("/v1/admin/backup", post(trigger_backup))
+        .route("/v1/admin/namespaces/:namespace", delete(purge_namespace))
+        .layer(limits.write_rate);
+    // A read-only replica doesn't route writes at all, so they get a 405.
+    let (writes, admin_writes) = if state.read_only() {
+        (Router::new(), Router::new())
+    } else {
+        (writes, admin_writes)
+    };
+    let mut managed = Router::new().merge(reads).merge(writes);
+    let mut admin = Router::new().merge(admin_reads).merge(admin_writes);
+    if !cfg.allowed_namespaces.is_empty() {
+        // Route layers see the matched `:namespace`. Added before basic auth, so that it runs
+        // after, and only authorized clients can find out which namespaces exist.
+        let allowed = Arc::new(cfg.allowed_namespaces.clone());
+        let layer = middleware::from_fn_with_state(allowed, require_allowed_namespace);
+        redirects = redirects.route_layer(layer.clone());
+        managed = managed.route_layer(layer.clone());
+        admin = admin.route_layer(layer);
+    }
+    if let Some(auth) = &cfg.basic_auth {
+        // A route layer, so that unknown paths are still 404s rather than 401s.
+        let token = Arc::new(auth.token());
+        managed = managed.route_layer(middleware::from_fn_with_state(token, require_basic_auth));
+    }
+    let mut api = Router::new().merge(redirects).merge(managed);
+    if let Some(auth) = &cfg.admin_auth {
+        let token = Arc::new(auth.token());
+        api =
+            api.merge(admin.route_layer(middleware::from_fn_with_state(token, require_basic_auth)));
+    }
+    if !cfg.allowed_origins.is_empty() {
+        // Outside the rate limits, so that preflights don't eat into a client's budget.
+        api = api.layer(cors(cfg.allowed_origins.clone()));
+    }
+    let route_prefix = cfg.route_prefix.clone();
+    let trim_trailing_slashes = cfg.trim_trailing_slashes;
+    let spec = Bytes::from(openapi(&cfg).to_json().expect("serialize openapi spec"));
+    // Per-route body limits (like the batch one above) take precedence over this default.
+    let app = Router::new()
+        .route("/", get(root))
+        .route(
+            "/openapi.json",
+            get(move || async move { ([(header::CONTENT_TYPE, "application/json")], spec) }),
+        )
+        .route("/docs", get(|| async { Html(SWAGGER_UI) }))
+        .merge(api)
+        .layer(Extension(Arc::new(IdempotencyKeys::new(
+            cfg.idempotency_window,
+        ))))
+        .layer(Extension(Arc::new(cfg)))
+        .layer(DefaultBodyLimit::max(limits.max_body_bytes))
+        .layer(TimeoutLayer::new(limits.request_timeout));
+    let app = match limits.max_concurrent_requests {
+        // Global, so that every route draws on the same permits rather than getting its own.
+        Some(max) => app.layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(overloaded))
+                .load_shed()
+                .layer(GlobalConcurrencyLimitLayer::new(max)),
+        ),
+        None => app,
+    };
+    let app = app
+        .layer(middleware::from_fn(html_errors))
+        .layer(middleware::from_fn(request_id))
+        // Outermost, so that it sees the final body. Its default predicate leaves tiny bodies
+        // (like redirects') and images (like QR codes) alone.
+        .layer(CompressionLayer::new())
+        .with_state(state);
+    let app = if route_prefix.is_empty() {
+        app
+    } else {
+        Router::new().nest(&route_prefix, app)
+    };
+    if trim_trailing_slashes {
+        // A layer on `app` itself would only run after routing, too late to change the route.
+        Router::new().fallback_service(NormalizePath::trim_trailing_slash(app))
+    } else {
+        app
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "flylinks",
+        description = "Short links, grouped into namespaces."
+    ),
+    paths(
+        redirect_link,
+        redirect_home,
+        resolve_link,
+        list_namespaces,
+        get_home,
+        set_home,
+        list_links,
+        create_link,
+        bulk_create_links,
+        bulk_delete_links,
+        validate_link,
+        search_links,
+        links_by_short_form,
+        export_bookmarks,
+        link_map,
+        export_links,
+        import_links,
+        random_link,
+        get_link,
+        update_link,
+        patch_link,
+        delete_link,
+        create_alias,
+        rename_link,
+        undelete_link,
+        disable_link,
+        enable_link,
+        link_stats,
+        link_qr,
+        reverse_lookup_query,
+        reverse_lookup,
+        reverse_lookup_batch,
+        trigger_backup,
+        backup_status,
+        reload,
+        purge_namespace,
+    ),
+    components(schemas(
+        ErrorBody,
+        ErrorCode,
+        Link,
+        NamespaceSummary,
+        ClickBucket,
+        BackupSummary,
+        BackupStatus,
+        ReloadResponse,
+        ListLinksResponse,
+        ListNamespacesResponse,
+        LinksByShortFormResponse,
+        NamespacedLink,
+        CreateLinkRequest,
+        CreatedLink,
+        DestinationCheck,
+        UpdateLinkRequest,
+        PatchLinkRequest,
+        UpdateLinkResponse,
+        BulkCreateRequest,
+        BulkCreateResponse,
+        BulkCreateResult,
+        BulkDeleteRequest,
+        BulkDeleteResponse,
+        ValidateLinkResponse,
+        ImportLinksResponse,
+        CreateAliasRequest,
+        RenameLinkRequest,
+        ResolveLinkResponse,
+        StatsResponse,
+        QrFormat,
+        ReverseLookupRequest,
+        ReverseLookupResponse,
+        ReverseLookupBatchRequest,
+        ReverseLookupBatchResponse,
+        ReverseLookupResult,
+        PurgeNamespaceResponse,
+    ))
+)]
+struct ApiDoc;
+
+/// The OpenAPI spec served at `/openapi.json`, adjusted for where and how this server is deployed.
+fn openapi(cfg: &ApiConfig) -> utoipa::openapi::OpenApi {
+    let mut spec = ApiDoc::openapi();
+    if !cfg.route_prefix.is_empty() {
+        spec.servers = Some(vec![utoipa::openapi::Server::new(&cfg.route_prefix)]);
+    }
+    let is_admin = |path: &str| path.starts_with("/v1/admin/");
+    if cfg.admin_auth.is_none() {
+        spec.paths.paths.retain(|path, _| !is_admin(path));
+    }
+    let mut require = |name: &str, applies: &dyn Fn(&str) -> bool| {
+        let scheme = SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Basic).build());
+        spec.components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(name, scheme);
+        let required = SecurityRequirement::new(name, Vec::<String>::new());
+        for (path, item) in spec.paths.paths.iter_mut() {
+            if !applies(path) {
+                continue;
+            }
+            for operation in item.operations.values_mut() {
+                operation.security = Some(vec![required.clone()]);
+            }
+        }
+    };
+    if cfg.basic_auth.is_some() {
+        // Everything but redirects and the admin routes needs it.
+        require("basic_auth", &|path| {
+            !path.starts_with("/v1/redirect/")
+                && !path.starts_with("/v1/resolve/")
+                && !is_admin(path)
+        });
+    }
+    if cfg.admin_auth.is_some() {
+        require("admin_auth", &is_admin);
+    }
+    spec
+}
+
+// Swagger UI itself comes from a CDN, so it needs no assets of our own.
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>flylinks API</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({ url: "openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##;
+
+/// Lets the server accept connections before the app exists: `/readyz` and every other request get
+/// a 503 until the app is `set`, after which `/readyz` is 200 and everything else goes to the app.
+/// That way an orchestrator can tell a server that's still downloading its db from a dead one.
+#[derive(Clone, Default)]
+pub struct Readiness {
+    // A Router isn't Sync, so it can't go in a OnceLock.
+    app: Arc<Mutex<Option<Router>>>,
+}
+impl Readiness {
+    /// Starts handing requests to `app`.
+    pub fn set(&self, app: Router) {
+        *self.app.lock().unwrap_or_else(PoisonError::into_inner) = Some(app);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.app().is_some()
+    }
+
+    fn app(&self) -> Option<Router> {
+        self.app
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Serves `/readyz` itself (outside any `route_prefix`), and everything else once ready.
+    pub fn router(&self) -> Router {
+        let gate = self.clone();
+        let readyz = {
+            let gate = self.clone();
+            move || async move {
+                if gate.is_ready() {
+                    "ready".into_response()
+                } else {
+                    not_ready().into_response()
+                }
+            }
+        };
+        Router::new()
+            .route("/readyz", get(readyz))
+            .fallback(move |request: Request| async move {
+                match gate.app() {
+                    Some(app) => app.oneshot(request).await.into_response(),
+                    None => not_ready().into_response(),
+                }
+            })
+    }
+}
+
+fn not_ready() -> AppError {
+    AppError::new(ErrorCode::NotReady, anyhow!("still loading the db"))
+}
+
+async fn overloaded(err: BoxError) -> Response {
+    if err.is::<Overloaded>() {
+        let mut response = AppError::new(
+            ErrorCode::Overloaded,
+            anyhow!("too many requests in flight, try again shortly"),
+        )
+        .into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+        return response;
+    }
+    AppError::from(anyhow!("{err}")).into_response()
+}
+
+/// Turns away requests whose Basic credentials don't match `token` (see `BasicAuth::token`).
+async fn require_basic_auth(
+    State(token): State<Arc<String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        .is_some_and(|(scheme, credentials)| {
+            scheme.eq_ignore_ascii_case("basic")
+                && constant_time_eq(credentials.trim().as_bytes(), token.as_bytes())
+        });
+    if authorized {
+        return next.run(request).await;
+    }
+    let mut response = AppError::new(
+        ErrorCode::Unauthorized,
+        anyhow!("missing or wrong credentials"),
+    )
+    .into_response();
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static("Basic realm=\"flylinks\", charset=\"UTF-8\""),
+    );
+    response
+}
+
+/// Turns away requests for a namespace outside `ApiConfig::allowed_namespaces`, as though it
+/// didn't exist. Routes without a `:namespace` are let through.
+async fn require_allowed_namespace(
+    State(allowed): State<Arc<HashSet<String>>>,
+    params: RawPathParams,
+    request: Request,
+    next: Next,
+) -> Response {
+    let namespace = params
+        .iter()
+        .find(|(name, _)| *name == "namespace")
+        .map(|(_, value)| value);
+    match namespace {
+        Some(namespace) if !allowed.contains(namespace) => AppError::new(
+            ErrorCode::NotFound,
+            anyhow!("no namespace {namespace} on this server"),
+        )
+        .into_response(),
+        _ => next.run(request).await,
+    }
+}
+
+/// Compares without short-circuiting, so that response times don't reveal how much of a guessed
+/// credential was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Lets `origins` make any API request, answering their preflight `OPTIONS` requests.
+fn cors(origins: Vec<HeaderValue>) -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::IF_NONE_MATCH,
+            HeaderName::from_static(IDEMPOTENCY_KEY),
+            HeaderName::from_static(REQUEST_ID),
+        ])
+        .expose_headers([
+            header::ETAG,
+            header::LOCATION,
+            header::RETRY_AFTER,
+            HeaderName::from_static(REQUEST_ID),
+        ])
+        .max_age(Duration::from_secs(60 * 60))
+}
+
+pub type RateLimitLayer = GovernorLayer<ClientIpKeyExtractor, NoOpMiddleware>;
+
+/// A token bucket per client IP that refills at `per_second` and holds at most `burst` tokens.
+/// See `ClientIpKeyExtractor` for which IP that is.
+pub fn rate_limit(
+    per_second: u32,
+    burst: u32,
+    trusted_proxies: &[IpNet],
+) -> anyhow::Result<RateLimitLayer> {
+    let config = GovernorConfigBuilder::default()
+        .key_extractor(ClientIpKeyExtractor {
+            trusted_proxies: trusted_proxies.into(),
+        })
+        .period(Duration::from_secs(1) / per_second)
+        .burst_size(burst)
+        .error_handler(|err| match err {
+            GovernorError::TooManyRequests { wait_time, .. } => rate_limited(wait_time),
+            err => AppError::from(anyhow!("rate limiter: {err}")).into_response(),
+        })
+        .finish()
+        .context("invalid rate limit")?;
+    Ok(GovernorLayer {
+        config: Arc::new(config),
+    })
+}
+
+fn rate_limited(wait_secs: u64) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, wait_secs.to_string())],
+        Json(json!({
+            "code": ErrorCode::RateLimited,
+            "msg": format!("rate limited, retry in {wait_secs}s"),
+        })),
+    )
+        .into_response()
+}
+
+/// Keys rate limits by the peer's IP, or, when the peer is one of `trusted_proxies`, by the client
+/// it says it's forwarding for. Anyone else's `X-Forwarded-For` is ignored, since a client could
+/// otherwise dodge its limit by making up a new one for every request.
+#[derive(Clone, Debug)]
+pub struct ClientIpKeyExtractor {
+    trusted_proxies: Arc<[IpNet]>,
+}
+impl KeyExtractor for ClientIpKeyExtractor {
+    type Key = IpAddr;
+
+    fn extract<T>(&self, request: &axum::http::Request<T>) -> Result<IpAddr, GovernorError> {
+        let ConnectInfo(peer) = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .ok_or(GovernorError::UnableToExtractKey)?;
+        Ok(forwarded_client(
+            &self.trusted_proxies,
+            peer.ip(),
+            request.headers(),
+        ))
+    }
+}
+
+/// The client behind any trusted proxies: the rightmost `X-Forwarded-For` entry that a trusted
+/// proxy added, since entries further left were made up by whoever sent them.
+fn forwarded_client(trusted_proxies: &[IpNet], peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    if !trusted(&peer) {
+        return peer;
+    }
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    for entry in forwarded.into_iter().rev() {
+        match entry.trim().parse() {
+            Ok(ip) if trusted(&ip) => continue,
+            Ok(ip) => return ip,
+            Err(_) => break,
+        }
+    }
+    peer
+}
+
+/// A token bucket per namespace, shared by every client writing to it, so that many clients (or
+/// one with many IPs) can't hammer a single namespace.
+pub type NamespaceRateLimiter = Arc<DefaultKeyedRateLimiter<String>>;
+
+/// A `NamespaceRateLimiter` that refills at `per_second` and holds at most `burst` tokens.
+pub fn namespace_rate_limit(per_second: u32, burst: u32) -> anyhow::Result<NamespaceRateLimiter> {
+    let per_second = NonZeroU32::new(per_second).context("invalid rate limit")?;
+    let burst = NonZeroU32::new(burst).context("invalid burst")?;
+    Ok(Arc::new(DefaultKeyedRateLimiter::keyed(
+        Quota::per_second(per_second).allow_burst(burst),
+    )))
+}
+
+async fn limit_namespace(
+    State(limiter): State<NamespaceRateLimiter>,
+    params: RawPathParams,
+    request: Request,
+    next: Next,
+) -> Response {
+    let namespace = params
+        .iter()
+        .find(|(name, _)| *name == "namespace")
+        .map(|(_, value)| value.to_owned());
+    if let Some(namespace) = namespace {
+        if let Err(not_until) = limiter.check_key(&namespace) {
+            let wait = not_until.wait_time_from(DefaultClock::default().now());
+            return rate_limited(wait.as_secs().max(1));
+        }
+    }
+    next.run(request).await
+}
+
+/// What went wrong, as a stable `code` in error responses that clients can branch on (unlike the
+/// human-readable `msg` next to it).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    NotFound,
+    Conflict,
+    /// The request itself is malformed or breaks a rule, and retrying it as-is won't help.
+    Validation,
+    Unauthorized,
+    PreconditionFailed,
+    /// The namespace is at its limit on links.
+    NamespaceFull,
+    /// The namespace doesn't exist yet, and the server is at its limit on namespaces.
+    TooManyNamespaces,
+    /// The link exists, but has been taken out of service for now.
+    LinkDisabled,
+    /// The long_form couldn't be reached, or answered with an error, when it was checked.
+    BadDestination,
+    RateLimited,
+    /// The server is too busy right now; retrying later should work.
+    Overloaded,
+    /// The server is still starting up (e.g. downloading the db).
+    NotReady,
+    /// Our fault, not the client's.
+    Internal,
+}
+impl ErrorCode {
+    fn status(self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Conflict => StatusCode::CONFLICT,
+            Self::Validation => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            Self::NamespaceFull | Self::TooManyNamespaces => StatusCode::FORBIDDEN,
+            Self::BadDestination => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::LinkDisabled | Self::Overloaded | Self::NotReady => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+type AppResult<T> = Result<T, AppError>;
+struct AppError {
+    code: ErrorCode,
+    status: StatusCode,
+    err: anyhow::Error,
+}
+impl AppError {
+    fn new(code: ErrorCode, err: anyhow::Error) -> Self {
+        Self {
+            code,
+            status: code.status(),
+            err,
+        }
+    }
+
+    /// Overrides the code's usual status, for the few errors that have a more specific one.
+    fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// A body that didn't deserialize, keeping axum's status for it (e.g. 415 for the wrong
+    /// Content-Type).
+    fn rejected(rejection: JsonRejection) -> Self {
+        Self::new(ErrorCode::Validation, anyhow!(rejection.body_text()))
+            .with_status(rejection.status())
+    }
+}
+/// What every error response looks like.
+#[derive(Serialize, ToSchema)]
+struct ErrorBody {
+    code: ErrorCode,
+    msg: String,
+    /// To quote when reporting the error; it's in the logs for the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+impl IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        let msg = self.err.to_string();
+        let body = ErrorBody {
+            code: self.code,
+            msg: msg.clone(),
+            request_id: CURRENT_REQUEST_ID.try_with(Clone::clone).ok(),
+        };
+        let mut response = (self.status, Json(body)).into_response();
+        // Lets `html_errors` re-render the error for browsers without parsing the JSON back out.
+        response.extensions_mut().insert(ErrorMessage(msg));
+        response
+    }
+}
+#[derive(Clone)]
+struct ErrorMessage(String);
+
+const REQUEST_ID: &str = "x-request-id";
+// Longer client-chosen ids are replaced, so they can't bloat every log line.
+const MAX_REQUEST_ID_LEN: usize = 128;
+tokio::task_local! {
+    /// The id of the request being handled, for `AppError` to put in error bodies.
+    static CURRENT_REQUEST_ID: String;
+    /// Whether the request being handled is a redirect or resolve.
+    static HANDLING_LOOKUP: bool;
+}
+
+/// Whether this is running on behalf of a redirect or resolve (rather than anything else, or no
+/// request at all), so that their logs can be filtered apart: there are a lot more of them.
+pub fn handling_lookup() -> bool {
+    HANDLING_LOOKUP.try_with(|lookup| *lookup).unwrap_or(false)
+}
+
+/// Gives each request an id (the client's own `X-Request-Id`, if it sent one, or else a new UUID),
+/// logs everything the request does under it, and echoes it back, so that a request a user reports
+/// can be found in the logs.
+async fn request_id(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_owned)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let path = request.uri().path();
+    let lookup = path.starts_with("/v1/redirect/") || path.starts_with("/v1/resolve/");
+    let handle = async {
+        // Created in scope, so that the span itself is filtered as part of the lookup. Just the
+        // path: queries can carry secrets (see `redact_logged_queries`).
+        let span = info_span!(
+            "request",
+            request_id = %id,
+            method = %request.method(),
+            path = request.uri().path(),
+        );
+        next.run(request).instrument(span).await
+    };
+    let mut response = HANDLING_LOOKUP
+        .scope(lookup, CURRENT_REQUEST_ID.scope(id.clone(), handle))
+        .await;
+    let value = HeaderValue::from_str(&id).expect("request ids are valid header values");
+    response.headers_mut().insert(REQUEST_ID, value);
+    response
+}
+
+/// Replaces JSON error bodies with a human-readable page for clients that ask for HTML, so that
+/// someone clicking a dead link in their browser doesn't get raw JSON.
+async fn html_errors(request: Request, next: Next) -> Response {
+    let wants_html = accepts_html(request.headers());
+    let response = next.run(request).await;
+    if !wants_html {
+        return response;
+    }
+    let Some(ErrorMessage(msg)) = response.extensions().get::<ErrorMessage>().cloned() else {
+        return response;
+    };
+    let status = response.status();
+    let title = html_escape(&status.to_string());
+    let page = format!(
+        "<!DOCTYPE html>\n\
+         <html>\n\
+         <head><meta charset=\"utf-8\"><title>{title}</title></head>\n\
+         <body>\n<h1>{title}</h1>\n<p>{}</p>\n</body>\n\
+         </html>\n",
+        html_escape(&msg)
+    );
+    let mut html = (status, Html(page)).into_response();
+    // Keep Retry-After and friends; only the body and its type change.
+    for (name, value) in response.headers() {
+        if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+            html.headers_mut().insert(name, value.clone());
+        }
+    }
+    html
+}
+
+/// Whether the client's Accept header lists `text/html`, as browsers' navigation requests do.
+/// API clients send `application/json`, `*/*`, or nothing, and keep getting JSON.
+fn accepts_html(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let is_html = parts
+                .next()
+                .is_some_and(|media| media.eq_ignore_ascii_case("text/html"));
+            let refused = parts.any(|param| param.replace(' ', "") == "q=0");
+            is_html && !refused
+        })
+}
+impl From<anyhow::Error> for AppError {
+    fn from(value: anyhow::Error) -> Self {
+        Self::new(ErrorCode::Internal, value)
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct ListLinksResponse {
+    links: Vec<Link>,
+}
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListLinksParams {
+    /// Also list deleted links, so they can be found and undeleted.
+    #[serde(default)]
+    include_deleted: bool,
+    /// Only links with this among their metadata `tags`.
+    tag: Option<String>,
+    /// Only links with this metadata `owner`.
+    owner: Option<String>,
+}
+/// Sends `Last-Modified` and honors `If-Modified-Since`, so that pollers only re-download a
+/// namespace's links once something about them has changed.
+#[utoipa::path(
+    get,
+    path = "/v1/links/{namespace}",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), ListLinksParams),
+    responses(
+        (status = 200, body = ListLinksResponse),
+        (status = 304, description = "Nothing changed since `If-Modified-Since`"),
+    )
+)]
+async fn list_links(
+    State(state): State<ServerState>,
+    Path(namespace): Path<String>,
+    Query(params): Query<ListLinksParams>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    // Read before the links, so that a write in between makes this too old rather than too new.
+    // HTTP dates only have whole seconds, so one for the current second could hide a change made
+    // later in that same second; it's left off until the second is over. The change times come
+    // from SQLite's clock, so this has to be the real time rather than `state.now()`.
+    let now = Utc::now();
+    let last_modified = state
+        .namespace_changed_at(namespace.clone(), true)?
+        .filter(|changed_at| changed_at.timestamp() < now.timestamp());
+    let not_modified = last_modified.is_some_and(|t| is_unmodified_since(&headers, t));
+    let mut response = if not_modified {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let filter = LinkFilter {
+            include_deleted: params.include_deleted,
+            tag: params.tag,
+            owner: params.owner,
+        };
+        let links = state.list_links(namespace, filter)?;
+        Json(ListLinksResponse { links }).into_response()
+    };
+    if let Some(last_modified) = last_modified {
+        let value = HeaderValue::from_str(&http_date(last_modified)).context("http date")?;
+        response.headers_mut().insert(header::LAST_MODIFIED, value);
+    }
+    Ok(response)
+}
+
+/// Formats `t` as an HTTP date, like `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(t: DateTime<Utc>) -> String {
+    t.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Whether the client's `If-Modified-Since` says its copy is at least as new as `last_modified`.
+/// Only meaningful without an `If-None-Match`, which takes precedence.
+fn is_unmodified_since(headers: &HeaderMap, last_modified: DateTime<Utc>) -> bool {
+    if headers.contains_key(header::IF_NONE_MATCH) {
+        return false;
+    }
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+        .is_some_and(|since| last_modified.timestamp() <= since.timestamp())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SearchParams {
+    prefix: String,
+    #[serde(default = "default_search_limit")]
+    limit: usize,
+}
+fn default_search_limit() -> usize {
+    20
+}
+const MAX_SEARCH_LIMIT: usize = 100;
+/// Backs type-ahead: the first few links (in lexical order) whose short_form starts with `prefix`.
+#[utoipa::path(
+    get,
+    path = "/v1/links/{namespace}/search",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), SearchParams),
+    responses((status = 200, body = ListLinksResponse))
+)]
+async fn search_links(
+    State(state): State<ServerState>,
+    Path(namespace): Path<String>,
+    Query(params): Query<SearchParams>,
+) -> AppResult<Json<ListLinksResponse>> {
+    let links = state.search_links(namespace, params.prefix, params.limit.min(MAX_SEARCH_LIMIT))?;
+    Ok(Json(ListLinksResponse { links }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct LinksByShortFormParams {
+    /// The `next_after` from the previous page.
+    after: Option<String>,
+    #[serde(default = "default_namespaces_limit")]
+    limit: usize,
+}
+#[derive(Serialize, ToSchema)]
+struct LinksByShortFormResponse {
+    links: Vec<NamespacedLink>,
+    /// Pass as `after` to get the next page. Absent on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_after: Option<String>,
+}
+#[derive(Serialize, ToSchema)]
+struct NamespacedLink {
+    namespace: String,
+    #[serde(flatten)]
+    link: Link,
+}
+/// Every namespace's live link with this short_form (at most one each), in namespace order. For
+/// spotting the same short_form in use by different teams.
+#[utoipa::path(
+    get,
+    path = "/v1/short-forms/{short_form}",
+    tag = "links",
+    params(("short_form" = String, Path, description = "May itself contain slashes."), LinksByShortFormParams),
+    responses((status = 200, body = LinksByShortFormResponse))
+)]
+async fn links_by_short_form(
+    State(state): State<ServerState>,
+    Path(short_form): Path<String>,
+    Query(params): Query<LinksByShortFormParams>,
+) -> AppResult<Json<LinksByShortFormResponse>> {
+    let limit = params.limit.clamp(1, MAX_NAMESPACES_LIMIT);
+    let links: Vec<_> = state
+        .links_by_short_form(short_form, params.after, limit)?
+        .into_iter()
+        .map(|(namespace, link)| NamespacedLink { namespace, link })
+        .collect();
+    let next_after = match links.last() {
+        Some(last) if links.len() == limit => Some(last.namespace.clone()),
+        _ => None,
+    };
+    Ok(Json(LinksByShortFormResponse { links, next_after }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListNamespacesParams {
+    /// The `next_after` from the previous page.
+    after: Option<String>,
+    #[serde(default = "default_namespaces_limit")]
+    limit: usize,
+}
+fn default_namespaces_limit() -> usize {
+    100
+}
+const MAX_NAMESPACES_LIMIT: usize = 1000;
+#[derive(Serialize, ToSchema)]
+struct ListNamespacesResponse {
+    namespaces: Vec<NamespaceSummary>,
+    /// Pass as `after` to get the next page. Absent on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_after: Option<String>,
+}
+#[utoipa::path(
+    get,
+    path = "/v1/namespaces",
+    tag = "namespaces",
+    params(ListNamespacesParams),
+    responses((status = 200, body = ListNamespacesResponse))
+)]
+async fn list_namespaces(
+    State(state): State<ServerState>,
+    Query(params): Query<ListNamespacesParams>,
+) -> AppResult<Json<ListNamespacesResponse>> {
+    let limit = params.limit.clamp(1, MAX_NAMESPACES_LIMIT);
+    let namespaces = state.list_namespaces(params.after, limit)?;
+    let next_after = match namespaces.last() {
+        Some(last) if namespaces.len() == limit => Some(last.namespace.clone()),
+        _ => None,
+    };
+    Ok(Json(ListNamespacesResponse {
+        namespaces,
+        next_after,
+    }))
+}
+
+#[derive(Clone, Deserialize, PartialEq, ToSchema)]
+struct CreateLinkRequest {
+    /// Made up if missing.
+    #[serde(default)]
+    short_form: Option<String>,
+    /// Without a scheme, `https://` is assumed.
+    #[serde(deserialize_with = "long_form")]
+    long_form: String,
+    #[serde(default)]
+    redirect_status: Option<u16>,
+    #[serde(default)]
+    redirect_headers: BTreeMap<String, String>,
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    metadata: serde_json::Map<String, Value>,
+    #[serde(default)]
+    forward_query: bool,
+    /// When the link was first made, for importing links from elsewhere. Only accepted where the
+    /// server allows it; defaults to now.
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+}
+impl CreateLinkRequest {
+    fn validate(&self, cfg: &ApiConfig, namespace: &str) -> AppResult<()> {
+        match self.problems(cfg, namespace).into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Everything that would stop the link from being created (short of a conflict), first
+    /// problem first. Both creates and `/validate` go through this.
+    fn problems(&self, cfg: &ApiConfig, namespace: &str) -> Vec<AppError> {
+        let mut problems = Vec::new();
+        if let Some(short_form) = &self.short_form {
+            if short_form == HOME_SHORT_FORM {
+                problems.push(AppError::new(
+                    ErrorCode::Validation,
+                    anyhow!(
+                        "short_form can't be empty; set the namespace's home link at /v1/home/{namespace}"
+                    ),
+                ));
+            } else {
+                problems.extend(cfg.check_short_form(short_form).err());
+                problems.extend(check_wildcard(short_form, &self.long_form).err());
+                problems.extend(
+                    cfg.check_not_own_short_url(namespace, short_form, &self.long_form)
+                        .err(),
+                );
+            }
+        }
+        problems.extend(cfg.check_long_form(&self.long_form).err());
+        problems
+            .extend(validate_redirect_options(self.redirect_status, &self.redirect_headers).err());
+        problems.extend(validate_metadata(&self.metadata).err());
+        if self.created_at.is_some() && !cfg.allow_created_at {
+            problems.push(AppError::new(
+                ErrorCode::Validation,
+                anyhow!("created_at can't be set on this server"),
+            ));
+        }
+        problems
+    }
+
+    fn to_link(&self, short_form: String, now: DateTime<Utc>) -> Link {
+        Link {
+            short_form,
+            long_form: self.long_form.clone(),
+            created_at: self.created_at.unwrap_or(now),
+            updated_at: now,
+            redirect_status: self.redirect_status,
+            redirect_headers: self.redirect_headers.clone(),
+            alias_of: None,
+            deleted_at: None,
+            last_accessed_at: None,
+            metadata: self.metadata.clone(),
+            forward_query: self.forward_query,
+            enabled: true,
+        }
+    }
+}
+/// Responds with the link as stored (including its server-assigned fields), and a `Location` that
+/// can be used to fetch it again.
+///
+/// With an `Idempotency-Key`, replaying a successful create (e.g. a retry after a dropped
+/// response) gets the original response again instead of a 409.
+#[utoipa::path(
+    post,
+    path = "/v1/links/{namespace}",
+    tag = "links",
+    params(
+        ("namespace" = String, Path, description = "Like a team or project."),
+        ("idempotency-key" = Option<String>, Header, description = "Makes retries of this create safe"),
+    ),
+    request_body = CreateLinkRequest,
+    responses(
+        (status = 201, body = CreatedLink, headers(("location" = String))),
+        (status = 400, body = ErrorBody),
+        (status = 403, description = "The namespace is at its limit on links", body = ErrorBody),
+        (status = 409, description = "The short_form is taken", body = ErrorBody),
+        (status = 422, description = "The idempotency key was used for a different request, or the long_form failed its check", body = ErrorBody),
+    )
+)]
+async fn create_link(
+    State(state): State<ServerState>,
+    Extension(cfg): Extension<Arc<ApiConfig>>,
+    Extension(idempotency_keys): Extension<Arc<IdempotencyKeys>>,
+    Path(namespace): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<CreateLinkRequest>,
+) -> AppResult<Response> {
+    request.validate(&cfg, &namespace)?;
+    let idempotency_key = match headers.get(IDEMPOTENCY_KEY) {
+        Some(key) => Some(
+            key.to_str()
+                .map_err(|_| {
+                    AppError::new(
+                        ErrorCode::Validation,
+                        anyhow!("invalid {IDEMPOTENCY_KEY} header"),
+                    )
+                })?
+                .to_owned(),
+        ),
+        None => None,
+    };
+    let replay = |keys: &IdempotentCreates| {
+        idempotency_keys.replay(keys, &cfg, &namespace, idempotency_key.as_deref(), &request)
+    };
+    let destination = match &cfg.verify_destinations {
+        Some(verifier) => {
+            // A replay gets the original response, without checking the long_form again. The
+            // lock isn't held across the check, which may take a while.
+            if let Some(response) = replay(&idempotency_keys.lock())? {
+                return Ok(response);
+            }
+            Some(check_destination(&cfg, verifier, &request.long_form).await?)
+        }
+        None => None,
+    };
+    // Held until the create is recorded, so a concurrent retry can't slip in between.
+    let mut keys = idempotency_keys.lock();
+    if let Some(response) = replay(&keys)? {
+        return Ok(response);
+    }
+    let now = state.now();
+    let link = match &request.short_form {
+        Some(short_form) => {
+            check_redirect_loop(&state, &cfg, &namespace, short_form, &request.long_form)?;
+            let link = request.to_link(short_form.clone(), now);
+            match state.create_link(namespace.clone(), link.clone())? {
+                WriteOutcome::Rejected => {
+                    return Err(AppError::new(
+                        ErrorCode::Conflict,
+                        anyhow!("link {namespace}/{} already exists", link.short_form),
+                    ))
+                }
+                WriteOutcome::NamespaceFull => return Err(namespace_full(&namespace)),
+                WriteOutcome::TooManyNamespaces => return Err(too_many_namespaces(&namespace)),
+                _ => {}
+            }
+            link
+        }
+        None => create_with_generated_short_form(&state, &cfg, &namespace, &request, now)?,
+    };
+    if let Some(key) = idempotency_key {
+        let window = idempotency_keys.window;
+        keys.retain(|_, prior| prior.created.elapsed() < window);
+        keys.insert(
+            (namespace.clone(), key),
+            IdempotentCreate {
+                created: Instant::now(),
+                request,
+                link: link.clone(),
+                destination: destination.clone(),
+            },
+        );
+    }
+    Ok(created_response(&cfg, &namespace, link, destination))
+}
+
+/// Checks the long_form of a link about to be created, turning it away if that fails and the
+/// verifier rejects. Wildcard long_forms aren't real URLs until a redirect fills them in, so
+/// they're let through unchecked.
+async fn check_destination(
+    cfg: &ApiConfig,
+    verifier: &DestinationVerifier,
+    long_form: &str,
+) -> AppResult<DestinationCheck> {
+    if long_form.contains(WILDCARD_PLACEHOLDER) {
+        return Ok(DestinationCheck {
+            ok: true,
+            status: None,
+            error: None,
+        });
+    }
+    let check = verifier.check(long_form).await;
+    if !check.ok {
+        let logged = if cfg.redact_logged_queries {
+            redact_query(long_form)
+        } else {
+            long_form.to_owned()
+        };
+        warn!(long_form = %logged, ?check, "long_form failed its check");
+        if verifier.rejects() {
+            let problem = match (&check.status, &check.error) {
+                (Some(status), _) => format!("answered {status}"),
+                (None, Some(err)) => format!("couldn't be reached: {err}"),
+                (None, None) => "couldn't be reached".to_owned(),
+            };
+            return Err(AppError::new(
+                ErrorCode::BadDestination,
+                anyhow!("long_form {long_form} {problem}"),
+            ));
+        }
+    }
+    Ok(check)
+}
+
+fn namespace_full(namespace: &str) -> AppError {
+    AppError::new(
+        ErrorCode::NamespaceFull,
+        anyhow!("{namespace} already has as many links as it's allowed; delete some first"),
+    )
+}
+
+fn too_many_namespaces(namespace: &str) -> AppError {
+    AppError::new(
+        ErrorCode::TooManyNamespaces,
+        anyhow!("can't start namespace {namespace}: there are already as many as allowed"),
+    )
+}
+
+fn link_disabled(namespace: &str, short_form: &str) -> AppError {
+    AppError::new(
+        ErrorCode::LinkDisabled,
+        anyhow!("link {namespace}/{short_form} is disabled"),
+    )
+}
+
+/// A created link, and what its long_form answered if it was checked.
+#[derive(Serialize, ToSchema)]
+struct CreatedLink {
+    #[serde(flatten)]
+    link: Link,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<DestinationCheck>,
+}
+fn created_response(
+    cfg: &ApiConfig,
+    namespace: &str,
+    link: Link,
+    destination: Option<DestinationCheck>,
+) -> Response {
+    let location = cfg.location(&format!(
+        "/v1/links/{}/{}",
+        escape_path(namespace),
+        escape_path(&link.short_form)
+    ));
+    (
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        Json(CreatedLink { link, destination }),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize, ToSchema)]
+struct BulkCreateRequest {
+    /// Each is a `CreateLinkRequest`, but kept raw so that one malformed row doesn't sink the rest.
+    #[schema(value_type = Vec<Object>)]
+    links: Vec<serde_json::Value>,
+}
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct BulkCreateParams {
+    #[serde(default)]
+    atomic: bool,
+}
+#[derive(Serialize, ToSchema)]
+struct BulkCreateResponse {
+    /// Whether the `ok` rows were written. Only ever false with `?atomic=true`.
+    committed: bool,
+    /// One per requested link, in the same order.
+    results: Vec<BulkCreateResult>,
+}
+#[derive(Serialize, ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum BulkCreateResult {
+    Ok {
+        /// What the long_form's host said, if destinations are verified.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        destination: Option<DestinationCheck>,
+    },
+    /// The short_form is already taken.
+    Conflict { reason: String },
+    /// The namespace had no room left for the row (or there was no room for a new namespace).
+    NamespaceFull { reason: String },
+    /// The row isn't a valid link, as it would be rejected by a single create.
+    Invalid { reason: String },
+    /// With `?atomic=true`, a valid row that wasn't written because some other row failed.
+    RolledBack,
+}
+#[derive(Serialize, ToSchema)]
+struct ValidateLinkResponse {
+    valid: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>,
+}
+/// Checks a would-be create without writing anything, reporting every problem at once rather than
+/// just the first. Whether the short_form is already taken isn't checked, since that can change
+/// before the real create anyway.
+#[utoipa::path(
+    post,
+    path = "/v1/links/{namespace}/validate",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project.")),
+    request_body = CreateLinkRequest,
+    responses((status = 200, body = ValidateLinkResponse))
+)]
+async fn validate_link(
+    Extension(cfg): Extension<Arc<ApiConfig>>,
+    Path(namespace): Path<String>,
+    Json(request): Json<CreateLinkRequest>,
+) -> Json<ValidateLinkResponse> {
+    let errors: Vec<String> = request
+        .problems(&cfg, &namespace)
+        .into_iter()
+        .map(|problem| problem.err.to_string())
+        .collect();
+    Json(ValidateLinkResponse {
+        valid: errors.is_empty(),
+        errors,
+    })
+}
+
+/// How many of a bulk create's destinations are checked at once.
+const DESTINATION_CHECKS_IN_FLIGHT: usize = 8;
+
+/// Creates many links at once, reporting per link. By default it's best-effort: every valid,
+/// non-conflicting link is created. With `?atomic=true`, links are only created if all of them can be.
+/// Destinations are verified per row, as for a single create.
+#[utoipa::path(
+    post,
+    path = "/v1/bulk/{namespace}",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), BulkCreateParams),
+    request_body = BulkCreateRequest,
+    responses((status = 207, body = BulkCreateResponse))
+)]
+async fn bulk_create_links(
+    State(state): State<ServerState>,
+    Extension(cfg): Extension<Arc<ApiConfig>>,
+    Path(namespace): Path<String>,
+    Query(params): Query<BulkCreateParams>,
+    Json(request): Json<BulkCreateRequest>,
+) -> AppResult<Response> {
+    let now = state.now();
+    let mut rows: Vec<Result<Link, String>> = request
+        .links
+        .into_iter()
+        .map(|row| {
+            let request: CreateLinkRequest =
+                serde_json::from_value(row).map_err(|err| err.to_string())?;
+            request
+                .validate(&cfg, &namespace)
+                .map_err(|err| err.err.to_string())?;
+            // Without it, the response would have no way to say which link is which.
+            let short_form = request
+                .short_form
+                .clone()
+                .ok_or("bulk creates need a short_form for every link")?;
+            check_redirect_loop(&state, &cfg, &namespace, &short_form, &request.long_form)
+                .map_err(|err| err.err.to_string())?;
+            Ok(request.to_link(short_form, now))
+        })
+        .collect();
+    // A few at a time, since each is an outbound request that can take up to the timeout.
+    let mut destinations: Vec<Option<DestinationCheck>> = vec![None; rows.len()];
+    if let Some(verifier) = &cfg.verify_destinations {
+        let long_forms: Vec<Option<String>> = rows
+            .iter()
+            .map(|row| row.as_ref().ok().map(|link| link.long_form.clone()))
+            .collect();
+        let cfg = &cfg;
+        let checks: Vec<_> = futures::stream::iter(long_forms)
+            .map(|long_form| async move {
+                match long_form {
+                    Some(long_form) => Some(check_destination(cfg, verifier, &long_form).await),
+                    None => None,
+                }
+            })
+            .buffered(DESTINATION_CHECKS_IN_FLIGHT)
+            .collect()
+            .await;
+        for ((row, destination), check) in rows.iter_mut().zip(&mut destinations).zip(checks) {
+            match check {
+                Some(Ok(check)) => *destination = Some(check),
+                Some(Err(err)) => *row = Err(err.err.to_string()),
+                None => {}
+            }
+        }
+    }
+    let any_invalid = rows.iter().any(Result::is_err);
+    let valid: Vec<Link> = rows
+        .iter()
+        .filter_map(|row| row.as_ref().ok().cloned())
+        .collect();
+    let mode = match (params.atomic, any_invalid) {
+        (false, _) => BulkMode::BestEffort,
+        (true, false) => BulkMode::Atomic,
+        // Still worth finding any conflicts, so that they can all be fixed in one go.
+        (true, true) => BulkMode::DryRun,
+    };
+    let outcomes = state.create_links(namespace.clone(), valid, mode)?;
+    let committed = match mode {
+        BulkMode::BestEffort => true,
+        BulkMode::Atomic => !outcomes.iter().any(WriteOutcome::is_rejection),
+        BulkMode::DryRun => false,
+    };
+    let mut outcomes = outcomes.into_iter();
+    let results = rows
+        .into_iter()
+        .zip(destinations)
+        .map(|(row, destination)| {
+            let link = match row {
+                Ok(link) => link,
+                Err(reason) => return BulkCreateResult::Invalid { reason },
+            };
+            // Outcomes are only for the valid rows, in order.
+            match outcomes.next() {
+                Some(WriteOutcome::Rejected) => BulkCreateResult::Conflict {
+                    reason: format!("link {namespace}/{} already exists", link.short_form),
+                },
+                Some(WriteOutcome::NamespaceFull) => BulkCreateResult::NamespaceFull {
+                    reason: namespace_full(&namespace).err.to_string(),
+                },
+                Some(WriteOutcome::TooManyNamespaces) => BulkCreateResult::NamespaceFull {
+                    reason: too_many_namespaces(&namespace).err.to_string(),
+                },
+                _ if !committed => BulkCreateResult::RolledBack,
+                _ => BulkCreateResult::Ok { destination },
+            }
+        })
+        .collect();
+    Ok((
+        StatusCode::MULTI_STATUS,
+        Json(BulkCreateResponse { committed, results }),
+    )
+        .into_response())
+}
+
+/// Backs up right away instead of waiting for a write to trigger it, e.g. just before risky
+/// maintenance. Responds once the backup is uploaded.
+#[utoipa::path(
+    post,
+    path = "/v1/admin/backup",
+    tag = "admin",
+    responses(
+        (status = 200, body = BackupSummary),
+        (status = 500, body = ErrorBody),
+    )
+)]
+async fn trigger_backup(State(state): State<ServerState>) -> AppResult<Json<BackupSummary>> {
+    Ok(Json(state.backup().await?))
+}
+
+/// For alerting when backups fall behind: writes since `last_backup_at` would be lost with the
+/// machine.
+#[utoipa::path(
+    get,
+    path = "/v1/admin/backup/status",
+    tag = "admin",
+    responses((status = 200, body = BackupStatus))
+)]
+async fn backup_status(State(state): State<ServerState>) -> Json<BackupStatus> {
+    Json(state.backup_status())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ReloadParams {
+    /// Required on a writer, to acknowledge that writes since the last backup will be lost.
+    #[serde(default)]
+    force: bool,
+}
+#[derive(Serialize, ToSchema)]
+struct ReloadResponse {
+    /// How many links the reloaded db has, deleted ones included.
+    links: usize,
+}
+/// Swaps in the db from the store without a restart, e.g. after restoring an old backup there by
+/// hand. On a writer, whatever was written since the last backup is lost, so it takes
+/// `?force=true`.
+#[utoipa::path(
+    post,
+    path = "/v1/admin/reload",
+    tag = "admin",
+    params(ReloadParams),
+    responses(
+        (status = 200, body = ReloadResponse),
+        (status = 400, description = "This is a writer, and `force` wasn't set", body = ErrorBody),
+        (status = 500, description = "The backup couldn't be downloaded or is corrupt; the old db stays in place", body = ErrorBody),
+    )
+)]
+async fn reload(
+    State(state): State<ServerState>,
+    Query(params): Query<ReloadParams>,
+) -> AppResult<Json<ReloadResponse>> {
+    if !state.read_only() && !params.force {
+        return Err(AppError::new(
+            ErrorCode::Validation,
+            anyhow!("reloading a writer loses anything written since its last backup; pass ?force=true to do it anyway"),
+        ));
+    }
+    let links = state.reload().await?;
+    Ok(Json(ReloadResponse { links }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PurgeNamespaceParams {
+    /// Must repeat the namespace, so that a purge can't happen by accident.
+    confirm: Option<String>,
+}
+#[derive(Serialize, ToSchema)]
+struct PurgeNamespaceResponse {
+    purged: usize,
+    /// Absent if the backup failed; the periodic backup will still pick the purge up.
+    backup: Option<BackupSummary>,
+}
+/// Deletes every link in the namespace for good (unlike deleting a link), then backs up so the
+/// purge is durable right away.
+#[utoipa::path(
+    delete,
+    path = "/v1/admin/namespaces/{namespace}",
+    tag = "admin",
+    params(("namespace" = String, Path, description = "Like a team or project."), PurgeNamespaceParams),
+    responses(
+        (status = 200, body = PurgeNamespaceResponse),
+        (status = 400, description = "`confirm` doesn't match", body = ErrorBody),
+    )
+)]
+async fn purge_namespace(
+    State(state): State<ServerState>,
+    Path(namespace): Path<String>,
+    Query(params): Query<PurgeNamespaceParams>,
+) -> AppResult<Json<PurgeNamespaceResponse>> {
+    if params.confirm.as_deref() != Some(namespace.as_str()) {
+        return Err(AppError::new(
+            ErrorCode::Validation,
+            anyhow!("purging {namespace} needs ?confirm={namespace}"),
+        ));
+    }
+    let purged = state.purge_namespace(namespace)?;
+    let backup = match state.backup().await {
+        Ok(summary) => Some(summary),
+        Err(err) => {
+            warn!(?err, "backup after purge failed");
+            None
+        }
+    };
+    Ok(Json(PurgeNamespaceResponse { purged, backup }))
+}
+
+// Each try collides with an existing link with probability (links in the namespace) / (possible
+// short_forms), so running out means the namespace is close to full.
+const GENERATED_SHORT_FORM_ATTEMPTS: usize = 5;
+/// Creates the link under a made-up short_form, making up another whenever it turns out to be
+/// taken (whether by an older link or a concurrent create).
+fn create_with_generated_short_form(
+    state: &ServerState,
+    cfg: &ApiConfig,
+    namespace: &str,
+    request: &CreateLinkRequest,
+    now: DateTime<Utc>,
+) -> AppResult<Link> {
+    for _ in 0..GENERATED_SHORT_FORM_ATTEMPTS {
+        let link = request.to_link(cfg.slugs.generate()?, now);
+        match state.create_link(namespace.to_owned(), link.clone())? {
+            WriteOutcome::Rejected => {}
+            WriteOutcome::NamespaceFull => return Err(namespace_full(namespace)),
+            WriteOutcome::TooManyNamespaces => return Err(too_many_namespaces(namespace)),
+            _ => return Ok(link),
+        }
+        info!(%namespace, short_form = %link.short_form, "generated short_form was taken");
+    }
+    Err(AppError::new(
+        ErrorCode::Conflict,
+        anyhow!(
+            "couldn't find a free short_form in {namespace} after \
+             {GENERATED_SHORT_FORM_ATTEMPTS} tries; choose one instead"
+        ),
+    ))
+}
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Recent successful creates, by namespace and `Idempotency-Key`. These only live in memory: a
+/// restart forgets them, which just means a retry straddling it gets a 409.
+struct IdempotencyKeys {
+    window: Duration,
+    entries: Mutex<IdempotentCreates>,
+}
+type IdempotentCreates = HashMap<(String, String), IdempotentCreate>;
+struct IdempotentCreate {
+    created: Instant,
+    request: CreateLinkRequest,
+    link: Link,
+    destination: Option<DestinationCheck>,
+}
+impl IdempotencyKeys {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Entries are only ever inserted or expired whole, so a panic can't leave one half-written.
+    fn lock(&self) -> MutexGuard<'_, IdempotentCreates> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The original response to a create with this key, if it's still remembered.
+    fn replay(
+        &self,
+        entries: &IdempotentCreates,
+        cfg: &ApiConfig,
+        namespace: &str,
+        key: Option<&str>,
+        request: &CreateLinkRequest,
+    ) -> AppResult<Option<Response>> {
+        let Some(key) = key else {
+            return Ok(None);
+        };
+        match entries.get(&(namespace.to_owned(), key.to_owned())) {
+            Some(prior) if prior.created.elapsed() < self.window => {
+                if prior.request != *request {
+                    return Err(AppError::new(
+                        ErrorCode::Conflict,
+                        anyhow!("{IDEMPOTENCY_KEY} {key} was already used for a different request"),
+                    )
+                    .with_status(StatusCode::UNPROCESSABLE_ENTITY));
+                }
+                Ok(Some(created_response(
+                    cfg,
+                    namespace,
+                    prior.link.clone(),
+                    prior.destination.clone(),
+                )))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+const REDIRECT_STATUSES: [u16; 4] = [301, 302, 307, 308];
+// Headers that the redirect itself determines, so a link can't override them.
+const RESERVED_REDIRECT_HEADERS: [HeaderName; 3] = [
+    header::LOCATION,
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+];
+fn validate_redirect_options(
+    status: Option<u16>,
+    headers: &BTreeMap<String, String>,
+) -> AppResult<()> {
+    let bad_request = |err| AppError::new(ErrorCode::Validation, err);
+    if let Some(status) = status {
+        if !REDIRECT_STATUSES.contains(&status) {
+            return Err(bad_request(anyhow!(
+                "redirect_status must be one of {REDIRECT_STATUSES:?}"
+            )));
+        }
+    }
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| bad_request(anyhow!("invalid header name {name:?}")))?;
+        if RESERVED_REDIRECT_HEADERS.contains(&name) {
+            return Err(bad_request(anyhow!("header {name} can't be overridden")));
+        }
+        HeaderValue::from_str(value)
+            .map_err(|_| bad_request(anyhow!("invalid value for header {name}")))?;
+    }
+    Ok(())
+}
+
+// Metadata is stored (and backed up) with every link, so it's for labels, not documents.
+const MAX_METADATA_BYTES: usize = 4096;
+/// Metadata can be any JSON object, except that `owner` and `tags` (which links can be filtered
+/// by) must be a string and an array of strings.
+fn validate_metadata(metadata: &serde_json::Map<String, Value>) -> AppResult<()> {
+    let bad_request = |err| AppError::new(ErrorCode::Validation, err);
+    let len = serde_json::to_string(metadata).map_or(0, |json| json.len());
+    if len > MAX_METADATA_BYTES {
+        return Err(bad_request(anyhow!(
+            "metadata is {len} bytes, more than the {MAX_METADATA_BYTES} allowed"
+        )));
+    }
+    if metadata
+        .get("owner")
+        .is_some_and(|owner| !owner.is_string())
+    {
+        return Err(bad_request(anyhow!("metadata owner must be a string")));
+    }
+    if let Some(tags) = metadata.get("tags") {
+        let valid = tags
+            .as_array()
+            .is_some_and(|tags| tags.iter().all(Value::is_string));
+        if !valid {
+            return Err(bad_request(anyhow!(
+                "metadata tags must be an array of strings"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Replaces everything about the link but its name: omitting the redirect options or metadata
+/// resets them.
+#[derive(Deserialize, ToSchema)]
+struct UpdateLinkRequest {
+    /// Without a scheme, `https://` is assumed.
+    #[serde(deserialize_with = "long_form")]
+    long_form: String,
+    #[serde(default)]
+    redirect_status: Option<u16>,
+    #[serde(default)]
+    redirect_headers: BTreeMap<String, String>,
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    metadata: serde_json::Map<String, Value>,
+    #[serde(default)]
+    forward_query: bool,
+    /// If set, the update only goes through while the link still points here.
+    #[serde(default)]
+    expected_long_form: Option<String>,
+}
+#[derive(Serialize, ToSchema)]
+struct UpdateLinkResponse {}
+#[utoipa::path(
+    put,
+    path = "/v1/links/{namespace}/{short_form}",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), ("short_form" = String, Path, description = "May itself contain slashes.")),
+    request_body = UpdateLinkRequest,
+    responses(
+        (status = 200, body = UpdateLinkResponse),
+        (status = 400, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 412, description = "The link no longer points at `expected_long_form`", body = ErrorBody),
+    )
+)]
+async fn update_link(
+    State(state): State<ServerState>,
+    Extension(cfg): Extension<Arc<ApiConfig>>,
+    Path((namespace, short_form)): Path<(String, String)>,
+    Json(request): Json<UpdateLinkRequest>,
+) -> AppResult<Json<UpdateLinkResponse>> {
+    cfg.check_long_form(&request.long_form)?;
+    check_wildcard(&short_form, &request.long_form)?;
+    check_redirect_loop(&state, &cfg, &namespace, &short_form, &request.long_form)?;
+    validate_redirect_options(request.redirect_status, &request.redirect_headers)?;
+    validate_metadata(&request.metadata)?;
+    let now = state.now();
+    let link = Link {
+        short_form: short_form.clone(),
+        long_form: request.long_form,
+        created_at: now,
+        updated_at: now,
+        redirect_status: request.redirect_status,
+        redirect_headers: request.redirect_headers,
+        alias_of: None,
+        deleted_at: None,
+        last_accessed_at: None,
+        metadata: request.metadata,
+        forward_query: request.forward_query,
+        enabled: true,
+    };
+    let outcome = match request.expected_long_form {
+        Some(expected) => state.update_link_if(namespace.clone(), link, expected)?,
+        None => state.update_link(namespace.clone(), link)?,
+    };
+    match outcome {
+        WriteOutcome::Rejected => Err(AppError::new(
+            ErrorCode::NotFound,
+            anyhow!("no link {namespace}/{short_form}"),
+        )),
+        WriteOutcome::PreconditionFailed => Err(AppError::new(
+            ErrorCode::PreconditionFailed,
+            anyhow!("{namespace}/{short_form} no longer points at the expected long_form"),
+        )),
+        WriteOutcome::NamespaceFull => Err(namespace_full(&namespace)),
+        WriteOutcome::TooManyNamespaces => Err(too_many_namespaces(&namespace)),
+        WriteOutcome::Changed | WriteOutcome::Unchanged => Ok(Json(UpdateLinkResponse {})),
+    }
+}
+
+/// Changes only the fields given. An explicit `null` clears `redirect_status`, `redirect_headers`,
+/// or `metadata`.
+#[derive(Deserialize, ToSchema)]
+struct PatchLinkRequest {
+    /// Without a scheme, `https://` is assumed.
+    #[serde(default, deserialize_with = "optional_long_form")]
+    long_form: Option<String>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<u16>)]
+    redirect_status: Option<Option<u16>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<Object>)]
+    redirect_headers: Option<Option<BTreeMap<String, String>>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<Object>)]
+    metadata: Option<Option<serde_json::Map<String, Value>>>,
+    #[serde(default)]
+    forward_query: Option<bool>,
+}
+
+/// Fills in `https:` for a long_form pasted without a scheme, like `example.com/foo` or
+/// `//example.com/foo`. Whether the result is a valid URL is left to `check_long_form`.
+fn with_default_scheme(long_form: String) -> String {
+    if let Some(rest) = long_form.strip_prefix("//") {
+        return format!("https://{rest}");
+    }
+    match url::Url::parse(&long_form) {
+        // A path is left to be refused, rather than having its first segment taken as a host.
+        Err(url::ParseError::RelativeUrlWithoutBase) if !long_form.starts_with('/') => {
+            format!("https://{long_form}")
+        }
+        // `example.com:8080/foo` parses with `example.com` as its scheme.
+        Ok(url) if url.scheme().contains('.') => format!("https://{long_form}"),
+        _ => long_form,
+    }
+}
+
+fn long_form<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    String::deserialize(deserializer).map(with_default_scheme)
+}
+
+fn optional_long_form<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    Option::<String>::deserialize(deserializer).map(|long_form| long_form.map(with_default_scheme))
+}
+
+/// Tells a field given as `null` (`Some(None)`) apart from one left out, which `#[serde(default)]`
+/// makes `None`.
+fn explicit_null<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[utoipa::path(
+    patch,
+    path = "/v1/links/{namespace}/{short_form}",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), ("short_form" = String, Path, description = "May itself contain slashes.")),
+    request_body = PatchLinkRequest,
+    responses(
+        (status = 200, body = UpdateLinkResponse),
+        (status = 400, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+async fn patch_link(
+    State(state): State<ServerState>,
+    Extension(cfg): Extension<Arc<ApiConfig>>,
+    Path((namespace, short_form)): Path<(String, String)>,
+    Json(request): Json<PatchLinkRequest>,
+) -> AppResult<Json<UpdateLinkResponse>> {
+    if let Some(long_form) = &request.long_form {
+        cfg.check_long_form(long_form)?;
+        check_wildcard(&short_form, long_form)?;
+        check_redirect_loop(&state, &cfg, &namespace, &short_form, long_form)?;
+    }
+    let patch = LinkPatch {
+        long_form: request.long_form,
+        redirect_status: request.redirect_status,
+        redirect_headers: request.redirect_headers.map(Option::unwrap_or_default),
+        metadata: request.metadata.map(Option::unwrap_or_default),
+        forward_query: request.forward_query,
+    };
+    validate_redirect_options(
+        patch.redirect_status.flatten(),
+        patch.redirect_headers.as_ref().unwrap_or(&BTreeMap::new()),
+    )?;
+    if let Some(metadata) = &patch.metadata {
+        validate_metadata(metadata)?;
+    }
+    let outcome = state.patch_link(namespace.clone(), short_form.clone(), patch, state.now())?;
+    if outcome == WriteOutcome::Rejected {
+        return Err(AppError::new(
+            ErrorCode::NotFound,
+            anyhow!("no link {namespace}/{short_form}"),
+        ));
+    }
+    Ok(Json(UpdateLinkResponse {}))
+}
+
+/// The POST counterpart of `get_link_resource`, for a link's `/alias`, `/rename`, and `/undelete`
+/// actions.
+async fn post_link_resource(
+    State(state): State<ServerState>,
+    Extension(cfg): Extension<Arc<ApiConfig>>,
+    Path((namespace, short_form)): Path<(String, String)>,
+    body: Bytes,
+) -> AppResult<Response> {
+    if let Some(short_form) = short_form.strip_suffix("/alias") {
+        let Json(request) =
+            Json::<CreateAliasRequest>::from_bytes(&body).map_err(AppError::rejected)?;
+        cfg.check_short_form(&request.alias)?;
+        return create_alias(state, &cfg, namespace, short_form.to_owned(), request).await;
+    }
+    if let Some(short_form) = short_form.strip_suffix("/rename") {
+        let Json(request) =
+            Json::<RenameLinkRequest>::from_bytes(&body).map_err(AppError::rejected)?;
+        cfg.check_short_form(&request.new_short_form)?;
+        return rename_link(state, namespace, short_form.to_owned(), request).await;
+    }
+    if let Some(short_form) = short_form.strip_suffix("/undelete") {
+        return undelete_link(state, namespace, short_form.to_owned()).await;
+    }
+    if let Some(short_form) = short_form.strip_suffix("/disable") {
+        return disable_link(state, namespace, short_form.to_owned()).await;
+    }
+    if let Some(short_form) = short_form.strip_suffix("/enable") {
+        return enable_link(state, namespace, short_form.to_owned()).await;
+    }
+    Err(AppError::new(
+        ErrorCode::NotFound,
+        anyhow!("nothing to POST to at {namespace}/{short_form}"),
+    ))
+}
+
+/// Only tombstones the link: it can be listed with `?include_deleted=true` and undeleted.
+#[utoipa::path(
+    delete,
+    path = "/v1/links/{namespace}/{short_form}",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), ("short_form" = String, Path, description = "May itself contain slashes.")),
+    responses(
+        (status = 204),
+        (status = 404, body = ErrorBody),
+    )
+)]
+async fn delete_link(
+    State(state): State<ServerState>,
+    Path((namespace, short_form)): Path<(String, String)>,
+) -> AppResult<StatusCode> {
+    let outcome = state.delete_link(namespace.clone(), short_form.clone(), state.now())?;
+    if outcome == WriteOutcome::Rejected {
+        return Err(AppError::new(
+            ErrorCode::NotFound,
+            anyhow!("no link {namespace}/{short_form}"),
+        ));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, ToSchema)]
+struct BulkDeleteRequest {
+    short_forms: Vec<String>,
+}
+#[derive(Serialize, ToSchema)]
+struct BulkDeleteResponse {
+    /// Short_forms that didn't exist (or were already deleted) aren't counted.
+    deleted: usize,
+}
+#[utoipa::path(
+    post,
+    path = "/v1/links/{namespace}/delete",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project.")),
+    request_body = BulkDeleteRequest,
+    responses((status = 200, body = BulkDeleteResponse))
+)]
+async fn bulk_delete_links(
+    State(state): State<ServerState>,
+    Path(namespace): Path<String>,
+    Json(request): Json<BulkDeleteRequest>,
+) -> AppResult<Json<BulkDeleteResponse>> {
+    let deleted = state.delete_links(namespace, &request.short_forms, state.now())?;
+    Ok(Json(BulkDeleteResponse { deleted }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/links/{namespace}/{short_form}/undelete",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), ("short_form" = String, Path, description = "May itself contain slashes.")),
+    responses(
+        (status = 200, body = Link),
+        (status = 404, description = "No deleted link by that name", body = ErrorBody),
+    )
+)]
+async fn undelete_link(
+    state: ServerState,
+    namespace: String,
+    short_form: String,
+) -> AppResult<Response> {
+    let outcome = state.undelete_link(namespace.clone(), short_form.clone())?;
+    if outcome == WriteOutcome::Rejected {
+        return Err(AppError::new(
+            ErrorCode::NotFound,
+            anyhow!("no deleted link {namespace}/{short_form}"),
+        ));
+    }
+    let link = state
+        .get_link(namespace, short_form)?
+        .context("link vanished after undelete")?;
+    Ok(Json(link).into_response())
+}
+
+/// Takes a link out of service without deleting it: redirects to it (and its aliases) get a 503
+/// until it's enabled again, while it's still listed and can still be edited.
+#[utoipa::path(
+    post,
+    path = "/v1/links/{namespace}/{short_form}/disable",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), ("short_form" = String, Path, description = "May itself contain slashes.")),
+    responses(
+        (status = 200, body = Link),
+        (status = 404, body = ErrorBody),
+    )
+)]
+async fn disable_link(
+    state: ServerState,
+    namespace: String,
+    short_form: String,
+) -> AppResult<Response> {
+    set_enabled(state, namespace, short_form, false).await
+}
+
+/// Puts a disabled link back in service.
+#[utoipa::path(
+    post,
+    path = "/v1/links/{namespace}/{short_form}/enable",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), ("short_form" = String, Path, description = "May itself contain slashes.")),
+    responses(
+        (status = 200, body = Link),
+        (status = 404, body = ErrorBody),
+    )
+)]
+async fn enable_link(
+    state: ServerState,
+    namespace: String,
+    short_form: String,
+) -> AppResult<Response> {
+    set_enabled(state, namespace, short_form, true).await
+}
+
+async fn set_enabled(
+    state: ServerState,
+    namespace: String,
+    short_form: String,
+    enabled: bool,
+) -> AppResult<Response> {
+    let outcome = state.set_enabled(namespace.clone(), short_form.clone(), enabled, state.now())?;
+    if outcome == WriteOutcome::Rejected {
+        return Err(AppError::new(
+            ErrorCode::NotFound,
+            anyhow!("no link {namespace}/{short_form}"),
+        ));
+    }
+    let link = state
+        .get_link(namespace, short_form)?
+        .context("link vanished after enabling or disabling")?;
+    Ok(Json(link).into_response())
+}
+
+#[derive(Deserialize, ToSchema)]
+struct RenameLinkRequest {
+    new_short_form: String,
+}
+#[utoipa::path(
+    post,
+    path = "/v1/links/{namespace}/{short_form}/rename",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), ("short_form" = String, Path, description = "May itself contain slashes.")),
+    request_body = RenameLinkRequest,
+    responses(
+        (status = 200, body = Link),
+        (status = 400, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    )
+)]
+async fn rename_link(
+    state: ServerState,
+    namespace: String,
+    short_form: String,
+    request: RenameLinkRequest,
+) -> AppResult<Response> {
+    let outcome = state.rename_link(
+        namespace.clone(),
+        short_form.clone(),
+        request.new_short_form.clone(),
+    )?;
+    match outcome {
+        RenameOutcome::Renamed(link) => Ok(Json(link).into_response()),
+        RenameOutcome::MissingSource => Err(AppError::new(
+            ErrorCode::NotFound,
+            anyhow!("no link {namespace}/{short_form}"),
+        )),
+        RenameOutcome::AlreadyExists => Err(AppError::new(
+            ErrorCode::Conflict,
+            anyhow!("link {namespace}/{} already exists", request.new_short_form),
+        )),
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CreateAliasRequest {
+    alias: String,
+}
+#[utoipa::path(
+    post,
+    path = "/v1/links/{namespace}/{short_form}/alias",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), ("short_form" = String, Path, description = "May itself contain slashes.")),
+    request_body = CreateAliasRequest,
+    responses(
+        (status = 201, body = Link, headers(("location" = String))),
+        (status = 400, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    )
+)]
+async fn create_alias(
+    state: ServerState,
+    cfg: &ApiConfig,
+    namespace: String,
+    short_form: String,
+    request: CreateAliasRequest,
+) -> AppResult<Response> {
+    let outcome = state.create_alias(
+        namespace.clone(),
+        short_form.clone(),
+        request.alias.clone(),
+        state.now(),
+    )?;
+    let link = match outcome {
+        AliasOutcome::Created(link) => link,
+        AliasOutcome::MissingTarget => {
+            return Err(AppError::new(
+                ErrorCode::NotFound,
+                anyhow!("no link {namespace}/{short_form}"),
+            ))
+        }
+        AliasOutcome::AlreadyExists => {
+            return Err(AppError::new(
+                ErrorCode::Conflict,
+                anyhow!("link {namespace}/{} already exists", request.alias),
+            ))
+        }
+        AliasOutcome::NamespaceFull => return Err(namespace_full(&namespace)),
+    };
+    Ok(created_response(cfg, &namespace, link, None))
+}
+
+/// `*short_form` may itself contain slashes, so a link's sub-resources (like `/qr`) can't be routed
+/// separately. Instead they're recognized here as the trailing segment of the wildcard capture.
+async fn get_link_resource(
+    State(state): State<ServerState>,
+    cfg: Extension<Arc<ApiConfig>>,
+    Path((namespace, short_form)): Path<(String, String)>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    if let Some(short_form) = short_form.strip_suffix("/qr") {
+        let query = match Query::try_from_uri(&uri) {
+            Ok(query) => query,
+            Err(rejection) => return rejection.into_response(),
+        };
+        let path = Path((namespace, short_form.to_owned()));
+        return link_qr(State(state), cfg, path, query, headers)
+            .await
+            .into_response();
+    }
+    if let Some(short_form) = short_form.strip_suffix("/stats") {
+        let query = match Query::try_from_uri(&uri) {
+            Ok(query) => query,
+            Err(rejection) => return rejection.into_response(),
+        };
+        let path = Path((namespace, short_form.to_owned()));
+        return link_stats(State(state), path, query).await.into_response();
+    }
+    get_link(State(state), Path((namespace, short_form)), headers)
+        .await
+        .into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/links/{namespace}/{short_form}",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), ("short_form" = String, Path, description = "May itself contain slashes.")),
+    responses(
+        (status = 200, body = Link),
+        (status = 304, description = "The client's `If-None-Match` is current"),
+        (status = 404, body = ErrorBody),
+    )
+)]
+async fn get_link(
+    State(state): State<ServerState>,
+    Path((namespace, short_form)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let Some(link) = state.get_link(namespace.clone(), short_form.clone())? else {
+        return Err(AppError::new(
+            ErrorCode::NotFound,
+            anyhow!("no link {namespace}/{short_form}"),
+        ));
+    };
+    let etag = link_etag(&link);
+    if is_not_modified(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    Ok(([(header::ETAG, etag)], Json(link)).into_response())
+}
+
+/// A live link picked at random from the namespace, say for a "link of the day". Takes time linear
+/// in the namespace's size, so it's for the occasional pick, not a hot path.
+#[utoipa::path(
+    get,
+    path = "/v1/links/{namespace}/random",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project.")),
+    responses(
+        (status = 200, body = Link),
+        (status = 404, description = "The namespace has no links", body = ErrorBody),
+    )
+)]
+async fn random_link(
+    State(state): State<ServerState>,
+    Path(namespace): Path<String>,
+) -> AppResult<Response> {
+    let Some(link) = state.random_link(namespace.clone())? else {
+        return Err(AppError::new(
+            ErrorCode::NotFound,
+            anyhow!("no links in {namespace}"),
+        ));
+    };
+    // Each request should get its own pick.
+    Ok(([(header::CACHE_CONTROL, "no-store")], Json(link)).into_response())
+}
+
+// Short enough that an edited link takes effect quickly even through a CDN, which can revalidate
+// cheaply with the ETag once it expires. A link can override this with its own `Cache-Control`.
+const REDIRECT_CACHE_CONTROL: &str = "public, max-age=60";
+
+/// The routes under `/v1/links/:namespace/` that aren't links, and so win over any link with the
+/// same short_form. No link can have one of these, or it could never be looked up or edited.
+const COLLECTION_ROUTES: &[&str] = &[
+    "bookmarks.html",
+    "delete",
+    "export",
+    "import",
+    "map",
+    "random",
+    "search",
+    "validate",
+];
+
+/// The last segments that `/v1/links/:namespace/*short_form` takes for a link's own sub-resources
+/// rather than as part of its short_form. No short_form can end in one of these either.
+const SUB_RESOURCES: &[&str] = &[
+    "alias", "disable", "enable", "qr", "rename", "stats", "undelete",
+];
+
+/// The reserved short_form of a namespace's home link, which its bare root redirects to. No
+/// ordinary link can have it, since short_forms can't be empty.
+const HOME_SHORT_FORM: &str = "";
+
+#[utoipa::path(
+    get,
+    path = "/v1/home/{namespace}",
+    tag = "namespaces",
+    params(("namespace" = String, Path, description = "Like a team or project.")),
+    responses(
+        (status = 200, body = Link),
+        (status = 304, description = "The client's `If-None-Match` is current"),
+        (status = 404, body = ErrorBody),
+    )
+)]
+async fn get_home(
+    State(state): State<ServerState>,
+    Path(namespace): Path<String>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    get_link(
+        State(state),
+        Path((namespace, HOME_SHORT_FORM.to_owned())),
+        headers,
+    )
+    .await
+}
+
+/// Creates or replaces the namespace's home link.
+#[utoipa::path(
+    put,
+    path = "/v1/home/{namespace}",
+    tag = "namespaces",
+    params(("namespace" = String, Path, description = "Like a team or project.")),
+    request_body = UpdateLinkRequest,
+    responses(
+        (status = 200, body = Link),
+        (status = 400, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    )
+)]
+async fn set_home(
+    State(state): State<ServerState>,
+    Extension(cfg): Extension<Arc<ApiConfig>>,
+    Path(namespace): Path<String>,
+    Json(request): Json<UpdateLinkRequest>,
+) -> AppResult<Json<Link>> {
+    cfg.check_long_form(&request.long_form)?;
+    check_redirect_loop(
+        &state,
+        &cfg,
+        &namespace,
+        HOME_SHORT_FORM,
+        &request.long_form,
+    )?;
+    validate_redirect_options(request.redirect_status, &request.redirect_headers)?;
+    validate_metadata(&request.metadata)?;
+    let now = state.now();
+    let link = Link {
+        short_form: HOME_SHORT_FORM.to_owned(),
+        long_form: request.long_form,
+        created_at: now,
+        updated_at: now,
+        redirect_status: request.redirect_status,
+        redirect_headers: request.redirect_headers,
+        alias_of: None,
+        deleted_at: None,
+        last_accessed_at: None,
+        metadata: request.metadata,
+        forward_query: request.forward_query,
+        enabled: true,
+    };
+    if state.update_link(namespace.clone(), link.clone())? == WriteOutcome::Rejected {
+        match state.create_link(namespace.clone(), link)? {
+            WriteOutcome::Rejected => {
+                return Err(AppError::new(
+                    ErrorCode::Conflict,
+                    anyhow!("home link for {namespace} was set concurrently"),
+                ))
+            }
+            WriteOutcome::NamespaceFull => return Err(namespace_full(&namespace)),
+            WriteOutcome::TooManyNamespaces => return Err(too_many_namespaces(&namespace)),
+            _ => {}
+        }
+    }
+    let link = state
+        .get_link(namespace, HOME_SHORT_FORM.to_owned())?
+        .context("home link vanished after write")?;
+    Ok(Json(link))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/redirect/{namespace}",
+    tag = "redirects",
+    params(("namespace" = String, Path, description = "Like a team or project.")),
+    responses(
+        (status = 307, description = "To the namespace's home link"),
+        (status = 404, description = "The namespace has no home link", body = ErrorBody),
+    )
+)]
+async fn redirect_home(
+    state: State<ServerState>,
+    cfg: Extension<Arc<ApiConfig>>,
+    Path(namespace): Path<String>,
+    method: Method,
+    query: RawQuery,
+    headers: HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+) -> AppResult<Response> {
+    let path = Path((namespace, HOME_SHORT_FORM.to_owned()));
+    redirect_link(state, cfg, path, method, query, headers, connect_info).await
+}
+
+// The query is skipped since it may carry secrets; see `redact_logged_queries`.
+#[utoipa::path(
+    get,
+    path = "/v1/redirect/{namespace}/{short_form}",
+    tag = "redirects",
+    params(("namespace" = String, Path, description = "Like a team or project."), ("short_form" = String, Path, description = "May itself contain slashes.")),
+    responses(
+        (status = 307, description = "To the link's long_form, or its own `redirect_status`. Also answers HEAD. With no exact match, a wildcard link (short_form ending in `/*`) covering the short_form redirects instead, with the part it matched substituted for `{}`."),
+        (status = 304, description = "The client's `If-None-Match` is current"),
+        (status = 404, description = "No such link, and no fallback configured", body = ErrorBody),
+    )
+)]
+#[tracing::instrument(skip(state, cfg, query, headers, connect_info))]
+async fn redirect_link(
+    State(state): State<ServerState>,
+    Extension(cfg): Extension<Arc<ApiConfig>>,
+    Path((namespace, short_form)): Path<(String, String)>,
+    method: Method,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+) -> AppResult<Response> {
+    let Some(link) = lookup_link(&state, &namespace, &short_form)? else {
+        if let Some(fallback) = cfg.fallback_redirect(&namespace, &short_form) {
+            info!(%fallback, "no link, redirecting to fallback");
+            // Not cacheable: the link might be created (perhaps from the fallback page) any moment.
+            return Ok((
+                [(header::CACHE_CONTROL, "no-store")],
+                Redirect::temporary(&fallback),
+            )
+                .into_response());
+        }
+        return Err(AppError::new(
+            ErrorCode::NotFound,
+            anyhow!("no link for {namespace}/{short_form}"),
+        ));
+    };
+    // Not a fallback case: the link exists, and the fallback page would offer to create it.
+    if !link.enabled {
+        return Err(link_disabled(&namespace, &link.short_form));
+    }
+    let destination = match query.as_deref() {
+        Some(query) if link.forward_query && !query.is_empty() => {
+            append_query(&link.long_form, query)
+        }
+        _ => link.long_form.clone(),
+    };
+    let long_form = if cfg.redact_logged_queries {
+        redact_query(&destination)
+    } else {
+        destination.clone()
+    };
+    info!(
+        %namespace,
+        short_form = %link.short_form,
+        %long_form,
+        client_ip = client_ip(&cfg, &headers, connect_info)
+            .map_or_else(|| "unknown".to_owned(), |ip| ip.to_string()),
+        "resolved redirect"
+    );
+    let etag = link_etag(&link);
+    if is_not_modified(&headers, &etag) {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [
+                (header::ETAG, etag),
+                (header::CACHE_CONTROL, REDIRECT_CACHE_CONTROL.to_owned()),
+            ],
+        )
+            .into_response());
+    }
+    // A redirect shouldn't fail just because it couldn't be counted.
+    if method != Method::HEAD || cfg.count_head_clicks {
+        if let Err(err) = state.record_click(namespace, link.short_form, state.now()) {
+            warn!(?err, "failed to record click");
+        }
+    }
+    let mut response = (
+        [
+            (header::ETAG, etag),
+            (header::CACHE_CONTROL, REDIRECT_CACHE_CONTROL.to_owned()),
+        ],
+        Redirect::temporary(&destination),
+    )
+        .into_response();
+    if let Some(status) = link
+        .redirect_status
+        .and_then(|s| StatusCode::from_u16(s).ok())
+    {
+        *response.status_mut() = status;
+    }
+    for (name, value) in &link.redirect_headers {
+        // Validated on write, so anything unparseable here predates the validation; skip it.
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    Ok(response)
+}
+
+/// `url` with its query string (but not its fragment) replaced by a placeholder.
+fn redact_query(url: &str) -> String {
+    let Some((base, rest)) = url.split_once('?') else {
+        return url.to_owned();
+    };
+    match rest.split_once('#') {
+        Some((_, fragment)) => format!("{base}?[redacted]#{fragment}"),
+        None => format!("{base}?[redacted]"),
+    }
+}
+
+/// The client's address as reported by a fronting proxy, falling back to the peer address.
+fn client_ip(
+    cfg: &ApiConfig,
+    headers: &HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+) -> Option<IpAddr> {
+    connect_info.map(|ConnectInfo(addr)| forwarded_client(&cfg.trusted_proxies, addr.ip(), headers))
+}
+
+#[derive(Serialize, ToSchema)]
+struct ResolveLinkResponse {
+    long_form: String,
+}
+/// Where a link points, for clients (like link previews) that want to peek without following the
+/// redirect. Unlike a redirect, this isn't counted as a click.
+#[utoipa::path(
+    get,
+    path = "/v1/resolve/{namespace}/{short_form}",
+    tag = "redirects",
+    params(("namespace" = String, Path, description = "Like a team or project."), ("short_form" = String, Path, description = "May itself contain slashes.")),
+    responses(
+        (status = 200, body = ResolveLinkResponse),
+        (status = 304, description = "The client's `If-None-Match` is current"),
+        (status = 404, body = ErrorBody),
+    )
+)]
+async fn resolve_link(
+    State(state): State<ServerState>,
+    Path((namespace, short_form)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let Some(link) = lookup_link(&state, &namespace, &short_form)? else {
+        return Err(AppError::new(
+            ErrorCode::NotFound,
+            anyhow!("no link for {namespace}/{short_form}"),
+        ));
+    };
+    if !link.enabled {
+        return Err(link_disabled(&namespace, &link.short_form));
+    }
+    let etag = link_etag(&link);
+    let not_modified = is_not_modified(&headers, &etag);
+    // Cached like the redirect it stands in for.
+    let headers = [
+        (header::ETAG, etag),
+        (header::CACHE_CONTROL, REDIRECT_CACHE_CONTROL.to_owned()),
+    ];
+    if not_modified {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+    Ok((
+        headers,
+        Json(ResolveLinkResponse {
+            long_form: link.long_form,
+        }),
+    )
+        .into_response())
+}
+
+/// Changes whenever the link does, since every edit bumps `updated_at`. Hashed with FNV-1a rather
+/// than std's `DefaultHasher`, whose output isn't stable across builds (and so across replicas).
+fn link_etag(link: &Link) -> String {
+    let updated_at = link.updated_at.to_rfc3339();
+    fnv_etag(link.long_form.bytes().chain([0]).chain(updated_at.bytes()))
+}
+
+fn fnv_etag(bytes: impl IntoIterator<Item = u8>) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("\"{hash:016x}\"")
+}
+
+/// Whether the client's `If-None-Match` says it already has the representation tagged `etag`.
+fn is_not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|candidate| candidate.trim())
+        // If-None-Match uses weak comparison, so a `W/` prefix doesn't matter.
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct StatsParams {
+    /// Defaults to a week before `to`.
+    from: Option<DateTime<Utc>>,
+    /// Defaults to now.
+    to: Option<DateTime<Utc>>,
+}
+#[derive(Serialize, ToSchema)]
+struct StatsResponse {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    total: u64,
+    /// Only hours with at least one click are listed.
+    buckets: Vec<ClickBucket>,
+}
+#[utoipa::path(
+    get,
+    path = "/v1/links/{namespace}/{short_form}/stats",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), ("short_form" = String, Path, description = "May itself contain slashes."), StatsParams),
+    responses(
+        (status = 200, body = StatsResponse),
+        (status = 400, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+async fn link_stats(
+    State(state): State<ServerState>,
+    Path((namespace, short_form)): Path<(String, String)>,
+    Query(params): Query<StatsParams>,
+) -> AppResult<Json<StatsResponse>> {
+    let to = params.to.unwrap_or_else(Utc::now);
+    let from = params.from.unwrap_or(to - TimeDelta::weeks(1));
+    if from > to {
+        return Err(AppError::new(
+            ErrorCode::Validation,
+            anyhow!("from ({from}) is after to ({to})"),
+        ));
+    }
+    let Some(link) = state.get_link(namespace.clone(), short_form.clone())? else {
+        return Err(AppError::new(
+            ErrorCode::NotFound,
+            anyhow!("no link {namespace}/{short_form}"),
+        ));
+    };
+    let buckets = state.click_stats(namespace, link.short_form, from, to)?;
+    let total = buckets.iter().map(|b| b.count).sum();
+    Ok(Json(StatsResponse {
+        from,
+        to,
+        total,
+        buckets,
+    }))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ReverseLookupRequest {
+    long_form: String,
+}
+#[derive(Serialize, ToSchema)]
+struct ReverseLookupResponse {
+    links: Vec<Link>,
+}
+#[utoipa::path(
+    post,
+    path = "/v1/reverse_lookup/{namespace}",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project.")),
+    request_body = ReverseLookupRequest,
+    responses((status = 200, body = ReverseLookupResponse))
+)]
+async fn reverse_lookup(
+    State(state): State<ServerState>,
+    Path(namespace): Path<String>,
+    Json(ReverseLookupRequest { long_form }): Json<ReverseLookupRequest>,
+) -> AppResult<Json<ReverseLookupResponse>> {
+    let links = state.reverse_lookup(namespace.clone(), long_form.clone())?;
+    Ok(Json(ReverseLookupResponse { links }))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ReverseLookupBatchRequest {
+    long_forms: Vec<String>,
+}
+#[derive(Serialize, ToSchema)]
+struct ReverseLookupBatchResponse {
+    /// One entry per requested long_form, in request order.
+    results: Vec<ReverseLookupResult>,
+}
+#[derive(Serialize, ToSchema)]
+struct ReverseLookupResult {
+    long_form: String,
+    links: Vec<Link>,
+}
+#[utoipa::path(
+    post,
+    path = "/v1/reverse_lookup/{namespace}/batch",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project.")),
+    request_body = ReverseLookupBatchRequest,
+    responses((status = 200, body = ReverseLookupBatchResponse))
+)]
+async fn reverse_lookup_batch(
+    State(state): State<ServerState>,
+    Path(namespace): Path<String>,
+    Json(ReverseLookupBatchRequest { long_forms }): Json<ReverseLookupBatchRequest>,
+) -> AppResult<Json<ReverseLookupBatchResponse>> {
+    let links = state.reverse_lookup_batch(namespace, &long_forms)?;
+    let results = long_forms
+        .into_iter()
+        .zip(links)
+        .map(|(long_form, links)| ReverseLookupResult { long_form, links })
+        .collect();
+    Ok(Json(ReverseLookupBatchResponse { results }))
+}
+
+/// `long_form` must be percent-encoded, since it will usually contain its own `?`, `&`, and `=`.
+/// Unknown parameters are rejected: a stray `&foo=bar` almost always means the client forgot to
+/// encode the destination URL, and silently looking up the truncated prefix would be wrong.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(deny_unknown_fields)]
+struct ReverseLookupQuery {
+    long_form: String,
+}
+#[utoipa::path(
+    get,
+    path = "/v1/reverse_lookup/{namespace}",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), ReverseLookupQuery),
+    responses(
+        (status = 200, body = ReverseLookupResponse),
+        (status = 400, description = "Unknown query parameters"),
+    )
+)]
+async fn reverse_lookup_query(
+    State(state): State<ServerState>,
+    Path(namespace): Path<String>,
+    Query(ReverseLookupQuery { long_form }): Query<ReverseLookupQuery>,
+) -> AppResult<Json<ReverseLookupResponse>> {
+    let links = state.reverse_lookup(namespace, long_form)?;
+    Ok(Json(ReverseLookupResponse { links }))
+}
+
+#[derive(Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum QrFormat {
+    #[default]
+    Png,
+    Svg,
+}
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct QrParams {
+    #[serde(default)]
+    format: QrFormat,
+    /// The minimum width and height of the rendered code, in pixels.
+    #[serde(default = "default_qr_size")]
+    size: u32,
+}
+fn default_qr_size() -> u32 {
+    256
+}
+const QR_SIZES: RangeInclusive<u32> = 32..=4096;
+#[utoipa::path(
+    get,
+    path = "/v1/links/{namespace}/{short_form}/qr",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project."), ("short_form" = String, Path, description = "May itself contain slashes."), QrParams),
+    responses(
+        (status = 200, description = "A QR code for the link's short URL", content(
+            ("image/png" = Vec<u8>),
+            ("image/svg+xml" = String),
+        )),
+        (status = 400, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+async fn link_qr(
+    State(state): State<ServerState>,
+    Extension(cfg): Extension<Arc<ApiConfig>>,
+    Path((namespace, short_form)): Path<(String, String)>,
+    Query(params): Query<QrParams>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    if !QR_SIZES.contains(&params.size) {
+        return Err(AppError::new(
+            ErrorCode::Validation,
+            anyhow!("size must be in {QR_SIZES:?}"),
+        ));
+    }
+    let Some(link) = state.get_link(namespace.clone(), short_form.clone())? else {
+        return Err(AppError::new(
+            ErrorCode::NotFound,
+            anyhow!("no link {namespace}/{short_form}"),
+        ));
+    };
+    let code = QrCode::new(cfg.short_url(&headers, &namespace, &link.short_form))
+        .context("encode qr code")?;
+    let response = match params.format {
+        QrFormat::Png => {
+            let image = code
+                .render::<Luma<u8>>()
+                .min_dimensions(params.size, params.size)
+                .build();
+            let mut png = Vec::new();
+            image
+                .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+                .context("encode png")?;
+            ([(header::CONTENT_TYPE, "image/png")], png).into_response()
+        }
+        QrFormat::Svg => {
+            let svg = code
+                .render::<svg::Color>()
+                .min_dimensions(params.size, params.size)
+                .build();
+            ([(header::CONTENT_TYPE, "image/svg+xml")], svg).into_response()
+        }
+    };
+    Ok(response)
+}
+
+/// Renders every link in the namespace in the Netscape bookmark format that Chrome and Firefox import.
+#[utoipa::path(
+    get,
+    path = "/v1/links/{namespace}/bookmarks.html",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project.")),
+    responses((status = 200, content_type = "text/html", body = String))
+)]
+async fn export_bookmarks(
+    State(state): State<ServerState>,
+    Extension(cfg): Extension<Arc<ApiConfig>>,
+    Path(namespace): Path<String>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let links = state.list_links(namespace.clone(), LinkFilter::default())?;
+    let mut html = String::from(
+        "<!DOCTYPE NETSCAPE-Bookmark-file-1>\n\
+         <META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">\n\
+         <TITLE>Bookmarks</TITLE>\n\
+         <H1>Bookmarks</H1>\n\
+         <DL><p>\n",
+    );
+    html.push_str(&format!(
+        "    <DT><H3>flylinks/{}</H3>\n    <DL><p>\n",
+        html_escape(&namespace)
+    ));
+    for link in links {
+        let href = cfg.short_url(&headers, &namespace, &link.short_form);
+        html.push_str(&format!(
+            "        <DT><A HREF=\"{}\" ADD_DATE=\"{}\">go/{}</A>\n",
+            html_escape(&href),
+            link.created_at.timestamp(),
+            html_escape(&link.short_form),
+        ));
+    }
+    html.push_str("    </DL><p>\n</DL><p>\n");
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"bookmarks.html\"",
+            ),
+        ],
+        html,
+    )
+        .into_response())
+}
+
+/// Dumps every link in the namespace, deleted ones included, as one JSON object per line in
+/// short_form order: a diffable file to keep in git and re-apply with `import`.
+/// `last_accessed_at` is left out, so that redirects alone don't change the dump.
+#[utoipa::path(
+    get,
+    path = "/v1/links/{namespace}/export",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project.")),
+    responses((status = 200, content_type = "application/x-ndjson", body = String))
+)]
+async fn export_links(
+    State(state): State<ServerState>,
+    Path(namespace): Path<String>,
+) -> AppResult<Response> {
+    let filter = LinkFilter {
+        include_deleted: true,
+        ..Default::default()
+    };
+    let mut links = state.list_links(namespace.clone(), filter)?;
+    // Stable whatever order the links were created in, so that diffs only show real changes.
+    links.sort_by(|a, b| a.short_form.cmp(&b.short_form));
+    let mut jsonl = String::new();
+    for mut link in links {
+        link.last_accessed_at = None;
+        jsonl.push_str(&serde_json::to_string(&link).context("serialize link")?);
+        jsonl.push('\n');
+    }
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson".to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{namespace}.jsonl\""),
+            ),
+        ],
+        jsonl,
+    )
+        .into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+struct ImportLinksResponse {
+    /// Links created or overwritten.
+    changed: usize,
+    /// Links already exactly as imported.
+    unchanged: usize,
+}
+/// Upserts the links in an `export` (one JSON link per line), keeping their timestamps, aliases
+/// and deletions as given. Re-importing the same file is a no-op, and links missing from it are
+/// left alone. Nothing is written unless every line is valid. Only with `allow_created_at`.
+#[utoipa::path(
+    post,
+    path = "/v1/links/{namespace}/import",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project.")),
+    request_body(content = String, content_type = "application/x-ndjson"),
+    responses(
+        (status = 200, body = ImportLinksResponse),
+        (status = 400, description = "A line isn't a valid link", body = ErrorBody),
+        (status = 403, description = "The namespace can't fit the new links", body = ErrorBody),
+    )
+)]
+async fn import_links(
+    State(state): State<ServerState>,
+    Extension(cfg): Extension<Arc<ApiConfig>>,
+    Path(namespace): Path<String>,
+    body: String,
+) -> AppResult<Json<ImportLinksResponse>> {
+    // Every line keeps its timestamps, so an import can backdate links just like a create can.
+    if !cfg.allow_created_at {
+        return Err(AppError::new(
+            ErrorCode::Validation,
+            anyhow!("imports keep their links' created_at, which can't be set on this server"),
+        ));
+    }
+    let invalid = |i: usize, err: anyhow::Error| {
+        AppError::new(ErrorCode::Validation, anyhow!("line {}: {err}", i + 1))
+    };
+    let mut lines = Vec::new();
+    for (i, line) in body.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let link: Link = serde_json::from_str(line).map_err(|err| invalid(i, err.into()))?;
+        let check = || -> AppResult<()> {
+            // The home link is the one link allowed an empty short_form.
+            if link.short_form != HOME_SHORT_FORM {
+                cfg.check_short_form(&link.short_form)?;
+            }
+            cfg.check_long_form(&link.long_form)?;
+            check_wildcard(&link.short_form, &link.long_form)?;
+            check_redirect_loop(&state, &cfg, &namespace, &link.short_form, &link.long_form)?;
+            validate_redirect_options(link.redirect_status, &link.redirect_headers)?;
+            validate_metadata(&link.metadata)
+        };
+        check().map_err(|err| invalid(i, err.err))?;
+        lines.push((i, link));
+    }
+    // An alias can follow a link from the same file, or one the namespace already has.
+    let short_forms: HashSet<&str> = lines.iter().map(|(_, link)| &*link.short_form).collect();
+    for (i, link) in &lines {
+        let Some(alias_of) = &link.alias_of else {
+            continue;
+        };
+        if !short_forms.contains(alias_of.as_str())
+            && state
+                .get_link(namespace.clone(), alias_of.clone())?
+                .is_none()
+        {
+            return Err(invalid(
+                *i,
+                anyhow!("alias_of {alias_of:?} isn't a link here or in the import"),
+            ));
+        }
+    }
+    let links = lines.into_iter().map(|(_, link)| link).collect();
+    let outcomes = state.import_links(namespace.clone(), links)?;
+    if outcomes.contains(&WriteOutcome::NamespaceFull) {
+        return Err(namespace_full(&namespace));
+    }
+    if outcomes.contains(&WriteOutcome::TooManyNamespaces) {
+        return Err(too_many_namespaces(&namespace));
+    }
+    let changed = outcomes
+        .iter()
+        .filter(|outcome| **outcome == WriteOutcome::Changed)
+        .count();
+    Ok(Json(ImportLinksResponse {
+        changed,
+        unchanged: outcomes.len() - changed,
+    }))
+}
+
+/// Every live link in the namespace as `{short_form: long_form}`, for clients that resolve links
+/// themselves (say, offline). Polling is cheap: it honors both `If-None-Match` and
+/// `If-Modified-Since`.
+#[utoipa::path(
+    get,
+    path = "/v1/links/{namespace}/map",
+    tag = "links",
+    params(("namespace" = String, Path, description = "Like a team or project.")),
+    responses(
+        (status = 200, body = HashMap<String, String>, headers(("etag" = String), ("last-modified" = String))),
+        (status = 304, description = "Nothing changed since `If-None-Match` or `If-Modified-Since`"),
+    )
+)]
+async fn link_map(
+    State(state): State<ServerState>,
+    Path(namespace): Path<String>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    // As in `list_links`, except that redirects don't change the map.
+    let now = Utc::now();
+    let last_modified = state
+        .namespace_changed_at(namespace.clone(), false)?
+        .filter(|changed_at| changed_at.timestamp() < now.timestamp());
+    let mut response = if last_modified.is_some_and(|t| is_unmodified_since(&headers, t)) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let map: BTreeMap<String, String> = state
+            .list_links(namespace, LinkFilter::default())?
+            .into_iter()
+            // Offline clients can't be told a link is disabled, so it's as though it's gone.
+            .filter(|link| link.enabled)
+            .map(|link| (link.short_form, link.long_form))
+            .collect();
+        let etag = fnv_etag(
+            map.iter()
+                .flat_map(|(k, v)| k.bytes().chain([0]).chain(v.bytes()).chain([0])),
+        );
+        let mut response = if is_not_modified(&headers, &etag) {
+            StatusCode::NOT_MODIFIED.into_response()
+        } else {
+            Json(map).into_response()
+        };
+        let value = HeaderValue::from_str(&etag).context("etag")?;
+        response.headers_mut().insert(header::ETAG, value);
+        response
+    };
+    if let Some(last_modified) = last_modified {
+        let value = HeaderValue::from_str(&http_date(last_modified)).context("http date")?;
+        response.headers_mut().insert(header::LAST_MODIFIED, value);
+    }
+    Ok(response)
+}
+
+/// Adds `query` to `url`'s own query string (if any), ahead of its fragment.
+fn append_query(url: &str, query: &str) -> String {
+    let (base, fragment) = match url.split_once('#') {
+        Some((base, fragment)) => (base, Some(fragment)),
+        None => (url, None),
+    };
+    let separator = match base.find('?') {
+        None => "?",
+        Some(i) if i + 1 == base.len() || base.ends_with('&') => "",
+        Some(_) => "&",
+    };
+    let mut out = format!("{base}{separator}{query}");
+    if let Some(fragment) = fragment {
+        out.push('#');
+        out.push_str(fragment);
+    }
+    out
+}
+
+/// The externally visible origin of this server, as seen by the client that sent `headers`.
+fn request_origin(headers: &HeaderMap) -> String {
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("http");
+    let host = headers
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("localhost");
+    format!("{scheme}://{host}")
+}
+
+fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_query_keeps_path_and_fragment() {
+        assert_eq!(
+            redact_query("https://example.com/a?token=secret#section"),
+            "https://example.com/a?[redacted]#section"
+        );
+        assert_eq!(
+            redact_query("https://example.com/a?token=secret"),
+            "https://example.com/a?[redacted]"
+        );
+        assert_eq!(
+            redact_query("https://example.com/a#b"),
+            "https://example.com/a#b"
+        );
+    }
+
+    #[test]
+    fn append_query_merges() {
+        let cases = [
+            ("https://example.com/d", "https://example.com/d?team=42"),
+            (
+                "https://example.com/d?x=1",
+                "https://example.com/d?x=1&team=42",
+            ),
+            ("https://example.com/d?", "https://example.com/d?team=42"),
+            (
+                "https://example.com/d?x=1&",
+                "https://example.com/d?x=1&team=42",
+            ),
+            (
+                "https://example.com/d#top",
+                "https://example.com/d?team=42#top",
+            ),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(append_query(url, "team=42"), expected);
+        }
+    }
+
+    #[test]
+    fn host_patterns() {
+        assert!(host_matches("example.com", "example.com"));
+        assert!(host_matches("example.com", "EXAMPLE.com."));
+        assert!(!host_matches("example.com", "www.example.com"));
+        assert!(host_matches("*.example.com", "www.example.com"));
+        assert!(host_matches("*.example.com", "a.b.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn accepts_html_only_when_listed() {
+        let accept = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_str(value).unwrap());
+            accepts_html(&headers)
+        };
+        assert!(accept("text/html,application/xhtml+xml,*/*;q=0.8"));
+        assert!(accept("application/json, Text/HTML; q=0.5"));
+        assert!(!accept("application/json"));
+        assert!(!accept("*/*"));
+        assert!(!accept("text/html;q=0, application/json"));
+        assert!(!accepts_html(&HeaderMap::new()));
+    }
+}
@@ -1,9 +1,15 @@
-use std::{io::Read, time::Duration};
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    time::Duration,
+};
 
 use anyhow::bail;
-use clap::{command, Parser, Subcommand};
+use backend::{persistence::purge_namespace_in, schema, store::StoreArgs};
+use clap::{Parser, Subcommand};
 use futures::StreamExt;
-use object_store::{aws::AmazonS3Builder, ObjectStore, PutPayload};
+use object_store::{ObjectStore, PutPayload};
+use serde::Serialize;
 use tracing::{info, info_span};
 use tracing_subscriber::fmt::format::FmtSpan;
 
@@ -13,15 +19,20 @@ async fn main() -> anyhow::Result<()> {
         .with_span_events(FmtSpan::CLOSE)
         .init();
 
-    let args = Args::try_parse()?;
-    dotenv::dotenv()?;
-    // from_env looks for:
-    // - AWS_ACCESS_KEY_ID
-    // - AWS_SECRET_ACCESS_KEY
-    let store = AmazonS3Builder::from_env()
-        .with_region("us-west-2")
-        .with_bucket_name("flylinks-us-west-2")
-        .build()?;
+    let mut args = Args::try_parse()?;
+    // Credentials are only needed for cloud stores, so a missing .env is fine.
+    if let Err(err) = dotenv::dotenv() {
+        if !err.not_found() {
+            return Err(err.into());
+        }
+    }
+    args.store
+        .s3_region
+        .get_or_insert_with(|| "us-west-2".to_owned());
+    args.store
+        .s3_bucket
+        .get_or_insert_with(|| "flylinks-us-west-2".to_owned());
+    let store = args.store.build()?;
 
     match args.cmd {
         Command::List { prefix } => {
@@ -69,28 +80,266 @@ async fn main() -> anyhow::Result<()> {
             let put_response = store.put(&path, PutPayload::from(content)).await?;
             info!(?put_response, "finished uploading backup");
         }
+        Command::Check { path } => {
+            let tmp = download(store.as_ref(), &path).await?;
+            let conn = open_read_only(tmp.path())?;
+            let integrity: Vec<String> = conn
+                .prepare("PRAGMA integrity_check")?
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            let fk_violations = conn
+                .prepare("PRAGMA foreign_key_check")?
+                .query_map([], |_| Ok(()))?
+                .collect::<Result<Vec<_>, _>>()?
+                .len();
+            let links: i64 = conn.query_row("SELECT COUNT(*) FROM links", [], |row| row.get(0))?;
+            let page_size: i64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+            let page_count: i64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+            println!("integrity_check: {}", integrity.join("; "));
+            println!("foreign_key_check: {fk_violations} violations");
+            println!("links: {links}");
+            println!("page_size: {page_size} bytes");
+            println!(
+                "total size: {} bytes ({page_count} pages)",
+                page_size * page_count
+            );
+            if integrity != ["ok"] || fk_violations > 0 {
+                bail!("backup at {path} failed verification");
+            }
+        }
+        Command::Stats { path } => {
+            let tmp = download(store.as_ref(), &path).await?;
+            let conn = open_read_only(tmp.path())?;
+            let counts: Vec<(String, i64)> = conn
+                .prepare(
+                    "SELECT namespace, COUNT(*) FROM links GROUP BY namespace ORDER BY 2 DESC",
+                )?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            let width = counts
+                .iter()
+                .map(|(namespace, _)| namespace.len())
+                .chain(["namespace".len(), "total".len()])
+                .max()
+                .unwrap_or_default();
+            println!("{:<width$}  links", "namespace");
+            for (namespace, count) in &counts {
+                println!("{namespace:<width$}  {count}");
+            }
+            let total: i64 = counts.iter().map(|(_, count)| count).sum();
+            println!("{:<width$}  {total}", "total");
+        }
+        Command::Import {
+            from_db,
+            into_db,
+            namespace,
+        } => {
+            let source = open_read_only(&from_db)?;
+            let has_created_at = check_import_source(&source)?;
+            let mut target = rusqlite::Connection::open(&into_db)?;
+            schema::ensure_schema(&mut target)?;
+            let now = chrono::Utc::now();
+            let created_at = if has_created_at { "created_at" } else { "NULL" };
+            let mut rows = source.prepare(&format!(
+                "SELECT short_form, long_form, {created_at} FROM links ORDER BY short_form"
+            ))?;
+            let mut rows = rows.query([])?;
+            let tx = target.transaction()?;
+            let (mut imported, mut conflicts) = (0, 0);
+            while let Some(row) = rows.next()? {
+                let short_form: String = row.get(0)?;
+                let long_form: String = row.get(1)?;
+                let created_at = row
+                    .get::<_, Option<chrono::DateTime<chrono::Utc>>>(2)?
+                    .unwrap_or(now);
+                if short_form.is_empty() {
+                    // The empty short_form is reserved for the namespace's home link.
+                    println!("skipped {namespace}/: empty short_form");
+                    continue;
+                }
+                let inserted = tx.execute(
+                    "
+                    INSERT INTO links (namespace, short_form, long_form, created_at, updated_at)
+                    VALUES (?1, ?2, ?3, ?4, ?4)
+                    ON CONFLICT DO NOTHING
+                    ",
+                    (&namespace, &short_form, &long_form, created_at),
+                )?;
+                if inserted == 0 {
+                    println!("conflict {namespace}/{short_form}: already exists, skipped");
+                    conflicts += 1;
+                } else {
+                    imported += 1;
+                }
+            }
+            tx.commit()?;
+            println!("imported {imported} links into {namespace}, skipped {conflicts} conflicts");
+        }
+        Command::Purge {
+            db,
+            namespace,
+            confirm,
+        } => {
+            if confirm != namespace {
+                bail!("--confirm must repeat the namespace ({namespace}) to purge it");
+            }
+            let mut conn = rusqlite::Connection::open(&db)?;
+            let tx = conn.transaction()?;
+            let purged = purge_namespace_in(&tx, &namespace)?;
+            tx.commit()?;
+            println!("purged {purged} links from {namespace}");
+        }
+        Command::Diff { db, path, json } => {
+            let live = read_links(&open_read_only(&db)?)?;
+            let tmp = download(store.as_ref(), &path).await?;
+            let backup = read_links(&open_read_only(tmp.path())?)?;
+            let diff = LinksDiff::new(&live, &backup);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&diff)?);
+            } else {
+                for link in &diff.only_in_db {
+                    println!(
+                        "+ {}/{} -> {}",
+                        link.namespace, link.short_form, link.long_form
+                    );
+                }
+                for link in &diff.only_in_backup {
+                    println!(
+                        "- {}/{} -> {}",
+                        link.namespace, link.short_form, link.long_form
+                    );
+                }
+                for change in &diff.changed {
+                    println!(
+                        "~ {}/{}: {} (backup) -> {} (db)",
+                        change.namespace,
+                        change.short_form,
+                        change.backup_long_form,
+                        change.db_long_form
+                    );
+                }
+                println!(
+                    "{} only in db, {} only in backup, {} changed, {} identical",
+                    diff.only_in_db.len(),
+                    diff.only_in_backup.len(),
+                    diff.changed.len(),
+                    diff.identical,
+                );
+            }
+        }
     };
     Ok(())
 }
 
-mod schema {
-    const DDL_LINKS_TABLE: &str = "
-        CREATE TABLE links (
-            namespace TEXT NOT NULL,
-            short_form TEXT NOT NULL,
-            long_form TEXT NOT NULL,
-            created_at TEXT NOT NULL,
-            PRIMARY KEY (namespace, short_form)
-        )
-    ";
-    pub(crate) fn ensure_schema(conn: &mut rusqlite::Connection) -> anyhow::Result<()> {
-        conn.execute(DDL_LINKS_TABLE, [])?;
-        Ok(())
+/// Downloads `path` into a temp file, which is deleted when dropped.
+async fn download(
+    store: &dyn ObjectStore,
+    path: &object_store::path::Path,
+) -> anyhow::Result<tempfile::NamedTempFile> {
+    let payload = store.get(path).await?.bytes().await?;
+    info!(len = payload.len(), "downloaded object");
+    let mut tmp = tempfile::NamedTempFile::new()?;
+    tmp.write_all(&payload)?;
+    Ok(tmp)
+}
+
+fn open_read_only(path: &std::path::Path) -> anyhow::Result<rusqlite::Connection> {
+    Ok(rusqlite::Connection::open_with_flags(
+        path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?)
+}
+
+/// Makes sure `conn` has a `links` table with text `short_form` and `long_form` columns, and
+/// returns whether it also has a `created_at` to carry over.
+fn check_import_source(conn: &rusqlite::Connection) -> anyhow::Result<bool> {
+    let columns: BTreeMap<String, String> = conn
+        .prepare("SELECT name, type FROM pragma_table_info('links')")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    if columns.is_empty() {
+        bail!("source db has no links table");
+    }
+    for required in ["short_form", "long_form"] {
+        match columns.get(required) {
+            Some(ty) if ty.eq_ignore_ascii_case("TEXT") => {}
+            Some(ty) => bail!("source links.{required} is {ty}, not TEXT"),
+            None => bail!("source links table has no {required} column"),
+        }
+    }
+    Ok(columns.contains_key("created_at"))
+}
+
+/// Every link's long_form, keyed by (namespace, short_form).
+type LinkMap = BTreeMap<(String, String), String>;
+fn read_links(conn: &rusqlite::Connection) -> anyhow::Result<LinkMap> {
+    let links = conn
+        .prepare("SELECT namespace, short_form, long_form FROM links")?
+        .query_map([], |row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?)))?
+        .collect::<Result<_, _>>()?;
+    Ok(links)
+}
+
+#[derive(Serialize)]
+struct LinksDiff {
+    only_in_db: Vec<DiffLink>,
+    only_in_backup: Vec<DiffLink>,
+    changed: Vec<DiffChange>,
+    identical: usize,
+}
+#[derive(Serialize)]
+struct DiffLink {
+    namespace: String,
+    short_form: String,
+    long_form: String,
+}
+#[derive(Serialize)]
+struct DiffChange {
+    namespace: String,
+    short_form: String,
+    db_long_form: String,
+    backup_long_form: String,
+}
+impl LinksDiff {
+    fn new(db: &LinkMap, backup: &LinkMap) -> Self {
+        let only_in = |a: &LinkMap, b: &LinkMap| {
+            a.iter()
+                .filter(|(key, _)| !b.contains_key(*key))
+                .map(|((namespace, short_form), long_form)| DiffLink {
+                    namespace: namespace.clone(),
+                    short_form: short_form.clone(),
+                    long_form: long_form.clone(),
+                })
+                .collect()
+        };
+        let mut changed = Vec::new();
+        let mut identical = 0;
+        for (key, db_long_form) in db {
+            match backup.get(key) {
+                Some(backup_long_form) if backup_long_form == db_long_form => identical += 1,
+                Some(backup_long_form) => changed.push(DiffChange {
+                    namespace: key.0.clone(),
+                    short_form: key.1.clone(),
+                    db_long_form: db_long_form.clone(),
+                    backup_long_form: backup_long_form.clone(),
+                }),
+                None => {}
+            }
+        }
+        Self {
+            only_in_db: only_in(db, backup),
+            only_in_backup: only_in(backup, db),
+            changed,
+            identical,
+        }
     }
 }
 
 #[derive(Parser)]
 struct Args {
+    #[command(flatten)]
+    store: StoreArgs,
+
     #[command(subcommand)]
     cmd: Command,
 }
@@ -123,4 +372,46 @@ enum Command {
         #[arg(long, help = "where in s3 to dump the backup")]
         path: object_store::path::Path,
     },
+    #[command(about = "Download a backup and verify that it's a healthy flylinks db")]
+    Check {
+        #[arg(long)]
+        path: object_store::path::Path,
+    },
+    #[command(about = "Count the links in each namespace of a backup, without changing anything")]
+    Stats {
+        #[arg(long)]
+        path: object_store::path::Path,
+    },
+    #[command(
+        about = "Copy the links from another tool's SQLite db into a namespace of a local db"
+    )]
+    Import {
+        #[arg(
+            long,
+            help = "db with a links table of at least short_form and long_form (and optionally created_at)"
+        )]
+        from_db: std::path::PathBuf,
+        #[arg(long)]
+        into_db: std::path::PathBuf,
+        #[arg(long)]
+        namespace: String,
+    },
+    #[command(about = "Permanently delete every link in a namespace of a local db")]
+    Purge {
+        #[arg(long)]
+        db: std::path::PathBuf,
+        #[arg(long)]
+        namespace: String,
+        #[arg(long, help = "the namespace again, to guard against typos")]
+        confirm: String,
+    },
+    #[command(about = "Compare the links in a local db against a backup")]
+    Diff {
+        #[arg(long)]
+        db: std::path::PathBuf,
+        #[arg(long)]
+        path: object_store::path::Path,
+        #[arg(long, help = "print the diff as JSON instead of a summary")]
+        json: bool,
+    },
 }
@@ -1,369 +1,878 @@
 use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::Arc,
     time::Duration,
 };
 
-use anyhow::{anyhow, Context};
-use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::{IntoResponse, Redirect, Response},
-    routing::{get, post},
-    Json, Router,
+use anyhow::Context;
+use axum::{http::HeaderValue, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use backend::{
+    api::{
+        handling_lookup, namespace_rate_limit, rate_limit, router, short_form_pattern, ApiConfig,
+        BasicAuth, Limits, Readiness, ServerState,
+    },
+    destinations::DestinationVerifier,
+    encryption::BackupKey,
+    persistence::{Config, Persistence, Pragmas, RestoreRetries, Synchronous, TempStore},
+    slugs::SlugGenerator,
+    store::StoreArgs,
+};
+use clap::{Parser, ValueEnum};
+use ipnet::IpNet;
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use tokio::{
+    net::TcpListener,
+    runtime::Handle,
+    signal::unix::{signal, SignalKind},
+    sync::Notify,
+    time::Instant,
+};
+use tracing::{info, warn};
+use tracing_subscriber::{
+    filter::{dynamic_filter_fn, LevelFilter},
+    fmt::format::FmtSpan,
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    Layer,
 };
-use chrono::Utc;
-use clap::Parser;
-use object_store::{aws::AmazonS3Builder, ObjectStore, PutPayload};
-use rusqlite::OptionalExtension;
-use serde::{Deserialize, Serialize};
-use serde_json::json;
-use tokio::{net::TcpListener, runtime::Handle, sync::Notify};
-use tracing::{info, info_span, warn};
-use tracing_subscriber::fmt::format::FmtSpan;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_span_events(FmtSpan::CLOSE)
-        .init();
     let args = Args::try_parse()?;
+    let tracer_provider = init_tracing(&args)?;
     if args.dotenv {
         dotenv::dotenv()?;
     }
 
+    // Listening before the db is loaded lets /readyz report that it's still loading.
+    info!("listening at {}...", args.address);
+    let listener = TcpListener::bind(&args.address).await?;
+    let readiness = Readiness::default();
+    let tls = args.tls_cert.clone().zip(args.tls_key.clone());
+    let mut server = tokio::spawn(serve(listener, readiness.router(), tls));
+
+    let store = args.store.build()?;
+    let mirrors = args.store.build_mirrors()?;
+    if let Some(quorum) = args.backup_quorum {
+        anyhow::ensure!(
+            quorum <= mirrors.len(),
+            "--backup-quorum can't be more than the {} --backup-mirror stores",
+            mirrors.len()
+        );
+    }
     let cfg = Config {
         db_path: args.db_path,
-        backup_staging_path: args.backup_staging_path,
-        s3_region: args.s3_region,
-        s3_bucket: args.s3_bucket,
+        backup_staging_dir: args.backup_staging_dir,
+        read_only: args.read_only,
         s3_path: args.s3_path,
+        case_insensitive_namespaces: args.case_insensitive_namespaces.into_iter().collect(),
+        auto_migrate: args.auto_migrate,
+        max_links_per_namespace: args.max_links_per_namespace,
+        max_namespaces: args.max_namespaces,
+        link_cache_size: args.link_cache_size,
+        pragmas: Pragmas {
+            synchronous: args.sqlite_synchronous,
+            cache_size: args.sqlite_cache_size,
+            temp_store: args.sqlite_temp_store,
+        },
+        backup_quorum: args.backup_quorum,
+        restore_retries: RestoreRetries {
+            max_attempts: args.restore_attempts,
+            deadline: args.restore_deadline_secs.map(Duration::from_secs),
+            ..RestoreRetries::default()
+        },
+        backup_key: args
+            .backup_encryption_key_file
+            .as_deref()
+            .map(BackupKey::from_file)
+            .transpose()?,
     };
-    let state: ServerState = Arc::new(Persistence::open(cfg).await?);
-    let _backup_handle = tokio::task::spawn_blocking({
-        let state = state.clone();
-        move || {
-            let h = Handle::current();
-            let mut count = 0;
-            loop {
-                info!("awaiting dirty bit");
-                h.block_on(state.dirty.notified());
-                count += 1;
-                info!(count, "triggering backup");
-                let content = match state.stage_backup() {
-                    Ok(content) => content,
-                    Err(err) => {
-                        warn!(?err, "failed to stage backup");
-                        continue;
+    let state: ServerState = tokio::select! {
+        state = Persistence::open(cfg, store) => Arc::new(state?.with_mirrors(mirrors)),
+        served = &mut server => {
+            served??;
+            info!("shut down before the db finished loading");
+            return Ok(());
+        }
+    };
+    let shutdown = Arc::new(Notify::new());
+    // A replica never writes, so instead of backing up it keeps pulling down the primary's backups.
+    let backup_handle = if args.read_only {
+        tokio::spawn({
+            let state = state.clone();
+            let period = Duration::from_secs(args.refresh_interval_secs);
+            async move {
+                let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+                loop {
+                    interval.tick().await;
+                    if let Err(err) = state.refresh().await {
+                        warn!(?err, "failed to refresh replica");
+                    }
+                }
+            }
+        });
+        None
+    } else {
+        Some(tokio::task::spawn_blocking({
+            let state = state.clone();
+            let shutdown = shutdown.clone();
+            let max_age = args.max_backup_age_secs.map(Duration::from_secs);
+            move || {
+                let h = Handle::current();
+                let mut count = 0;
+                loop {
+                    info!("awaiting dirty bit");
+                    let shutting_down = h.block_on(async {
+                        let dirty = async {
+                            match max_age {
+                                Some(max_age) => {
+                                    let waited = tokio::time::timeout(max_age, state.wait_dirty());
+                                    if waited.await.is_err() {
+                                        info!(?max_age, "no writes lately, backing up anyway");
+                                    }
+                                }
+                                None => state.wait_dirty().await,
+                            }
+                        };
+                        tokio::select! {
+                            biased;
+                            _ = shutdown.notified() => true,
+                            _ = dirty => false,
+                        }
+                    });
+                    count += 1;
+                    info!(count, shutting_down, "triggering backup");
+                    if let Err(err) = h.block_on(state.backup()) {
+                        warn!(?err, "failed to back up");
                     }
-                };
-                if let Err(err) = h.block_on(state.backup_to_s3(content)) {
-                    warn!(?err, "failed to upload backup");
-                    continue;
+                    if shutting_down {
+                        break;
+                    }
+                }
+            }
+        }))
+    };
+    let read_limit = rate_limit(args.read_rate_limit, args.read_burst, &args.trusted_proxies)?;
+    let write_limit = rate_limit(
+        args.write_rate_limit,
+        args.write_burst,
+        &args.trusted_proxies,
+    )?;
+    let namespace_write_limit =
+        namespace_rate_limit(args.namespace_write_rate_limit, args.namespace_write_burst)?;
+    // Without a periodic sweep the limiters remember every client IP (and namespace) they have
+    // ever seen.
+    tokio::spawn({
+        let limiters = [read_limit.config.clone(), write_limit.config.clone()];
+        let namespace_limiter = namespace_write_limit.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                for limiter in &limiters {
+                    limiter.limiter().retain_recent();
                 }
+                namespace_limiter.retain_recent();
             }
         }
     });
-    // It's important that `*short_form` is a wildcard capture so that we support keys with slashes in them
-    let app = Router::new()
-        .route("/", get(|| async { "Hello, World!" }))
-        .route("/v1/links/:namespace", get(list_links))
-        .route("/v1/links/:namespace", post(create_link))
-        .route("/v1/links/:namespace/*short_form", get(get_link))
-        .route("/v1/reverse_lookup/:namespace", post(reverse_lookup))
-        .route("/v1/redirect/:namespace/*short_form+", get(redirect_link))
-        .with_state(state);
+    let slugs = match &args.slug_blocklist {
+        Some(path) => SlugGenerator::from_blocklist_file(path)?,
+        None => SlugGenerator::default(),
+    };
+    let slugs = match &args.slug_alphabet {
+        Some(alphabet) => slugs.with_alphabet(alphabet).context("--slug-alphabet")?,
+        None => slugs,
+    };
+    let slugs = slugs
+        .with_length(args.slug_length)
+        .context("--slug-length")?;
+    let app = router(
+        state,
+        Limits {
+            read_rate: read_limit,
+            write_rate: write_limit,
+            namespace_write_rate: Some(namespace_write_limit),
+            max_body_bytes: args.max_body_bytes,
+            max_batch_body_bytes: args.max_batch_body_bytes,
+            request_timeout: Duration::from_secs(args.request_timeout_secs),
+            max_concurrent_requests: args.max_concurrent_requests,
+        },
+        ApiConfig {
+            redact_logged_queries: args.redact_logged_queries,
+            count_head_clicks: args.count_head_clicks,
+            allow_created_at: args.allow_created_at,
+            root_redirect: args.root_redirect,
+            trim_trailing_slashes: !args.keep_trailing_slashes,
+            fallback_url: args.fallback_url,
+            namespace_fallback_urls: args.namespace_fallback_urls.into_iter().collect(),
+            idempotency_window: Duration::from_secs(args.idempotency_window_secs),
+            max_short_form_len: args.max_short_form_len,
+            short_form_pattern: args
+                .short_form_pattern
+                .as_deref()
+                .map(short_form_pattern)
+                .transpose()
+                .context("--short-form-pattern")?,
+            max_long_form_len: args.max_long_form_len,
+            allowed_origins: args.allowed_origins,
+            basic_auth: args.basic_auth,
+            admin_auth: args.admin_auth,
+            trusted_proxies: args.trusted_proxies,
+            allowed_hosts: args.allowed_hosts,
+            allowed_namespaces: args.allowed_namespaces.into_iter().collect(),
+            blocked_hosts: args.blocked_hosts,
+            route_prefix: args.route_prefix,
+            public_base_url: args.public_base_url,
+            slugs,
+            verify_destinations: args
+                .verify_destinations
+                .map(|mode| {
+                    DestinationVerifier::new(
+                        Duration::from_secs(args.verify_destinations_timeout_secs),
+                        mode == VerifyDestinations::Reject,
+                        args.verify_private_destinations,
+                    )
+                })
+                .transpose()?,
+        },
+    );
 
-    info!("listening at {}...", args.address);
-    let listener = TcpListener::bind(args.address).await?;
-    axum::serve(listener, app).await?;
+    readiness.set(app);
+    info!("db loaded, ready to serve");
+    server.await??;
 
+    // In-flight requests have drained, so this final backup captures every accepted write.
+    info!("server stopped, flushing final backup");
+    if let Some(backup_handle) = backup_handle {
+        shutdown.notify_one();
+        backup_handle.await?;
+    }
+    info!("final backup complete, exiting");
+    if let Some(provider) = tracer_provider {
+        // Flushes whatever spans are still batched up. The backup is already safe, so an
+        // unreachable collector isn't worth exiting with an error over.
+        if let Err(err) = provider.shutdown() {
+            warn!(?err, "failed to flush spans");
+        }
+    }
     Ok(())
 }
 
-type ServerState = Arc<Persistence>;
-struct Persistence {
-    cfg: Config,
-    conn: Mutex<rusqlite::Connection>,
-    store: object_store::aws::AmazonS3,
-    dirty: Notify,
-}
-#[derive(Debug)]
-struct Config {
-    db_path: std::path::PathBuf,
-    backup_staging_path: std::path::PathBuf,
-    s3_region: String,
-    s3_bucket: String,
-    s3_path: String,
-}
-impl Persistence {
-    #[tracing::instrument]
-    async fn open(cfg: Config) -> anyhow::Result<Self> {
-        let _ = std::fs::remove_file(&cfg.db_path);
-        let _ = std::fs::remove_file(&cfg.backup_staging_path);
-        let store = AmazonS3Builder::from_env()
-            .with_region(&cfg.s3_region)
-            .with_bucket_name(&cfg.s3_bucket)
-            .build()
-            .context("init s3")?;
-        {
-            let get_response = store
-                .get(&cfg.s3_path.as_str().into())
+/// Serves `app` until a shutdown signal, over HTTPS if given a certificate and key.
+async fn serve(
+    listener: TcpListener,
+    app: Router,
+    tls: Option<(PathBuf, PathBuf)>,
+) -> anyhow::Result<()> {
+    // The rate limiters fall back to the peer address when there's no X-Forwarded-For.
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls {
+        Some((cert, key)) => {
+            // Only fails if a provider is already installed, which is just as good.
+            let _ = rustls::crypto::ring::default_provider().install_default();
+            let tls = RustlsConfig::from_pem_file(&cert, &key)
                 .await
-                .context("initial get db from s3")?;
-            info!(?get_response, "found object");
-            let payload = get_response.bytes().await?;
-            info!(len = payload.len(), "downloaded object");
-            std::fs::write(&cfg.db_path, payload)?;
+                .context("load TLS certificate")?;
+            tokio::spawn(reload_tls_on_sighup(tls.clone(), cert, key));
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown_signal().await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            axum_server::from_tcp_rustls(listener.into_std()?, tls)
+                .handle(handle)
+                .serve(app)
+                .await?;
+        }
+        None => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal())
+                .await?;
         }
-        let conn = Mutex::new(rusqlite::Connection::open(&cfg.db_path)?);
-        Ok(Self {
-            cfg,
-            conn,
-            store,
-            dirty: Notify::new(),
-        })
-    }
-
-    #[tracing::instrument(skip(self))]
-    fn stage_backup(&self) -> anyhow::Result<Vec<u8>> {
-        let conn = self.conn.lock().unwrap();
-        let mut backup_conn = rusqlite::Connection::open(&self.cfg.backup_staging_path)?;
-        let _span = info_span!("backup").entered();
-        let b = rusqlite::backup::Backup::new(&conn, &mut backup_conn)?;
-        b.run_to_completion(
-            5,
-            Duration::ZERO,
-            Some(|p| {
-                info!(?p, "backup tick");
-            }),
-        )?;
-        let content = std::fs::read(&self.cfg.backup_staging_path)?;
-        info!(size = content.len(), "read backup into memory");
-        Ok(content)
-    }
-
-    #[tracing::instrument(skip(self, content))]
-    async fn backup_to_s3(&self, content: Vec<u8>) -> anyhow::Result<()> {
-        let put_response = self
-            .store
-            .put(&self.cfg.s3_path.as_str().into(), PutPayload::from(content))
-            .await?;
-        info!(?put_response, "finished uploading backup");
-        Ok(())
-    }
-
-    #[tracing::instrument(skip(self))]
-    pub fn list_links(&self, namespace: String) -> anyhow::Result<Vec<Link>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = {
-            let _span = info_span!("prepare_statement").entered();
-            conn.prepare("SELECT short_form, long_form, created_at FROM links WHERE namespace = ?")?
-        };
-        let links: Vec<Link> = {
-            let _span = info_span!("query_map").entered();
-            stmt.query_map([namespace], |row| {
-                let link: Link = Link {
-                    short_form: row.get(0)?,
-                    long_form: row.get(1)?,
-                    created_at: row.get(2)?,
-                };
-                Ok(link)
-            })?
-            .collect::<Result<Vec<_>, _>>()?
-        };
-        Ok(links)
-    }
-
-    #[tracing::instrument(skip(self))]
-    pub fn get_link(&self, namespace: String, short_form: String) -> anyhow::Result<Option<Link>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = {
-            let _span = info_span!("prepare_statement").entered();
-            conn.prepare(
-                "SELECT long_form, created_at FROM links WHERE namespace = ? AND short_form = ?",
-            )?
-        };
-        let links: Option<Link> = {
-            let _span = info_span!("query_row").entered();
-            stmt.query_row([namespace, short_form.clone()], |row| {
-                let link: Link = Link {
-                    short_form,
-                    long_form: row.get(0)?,
-                    created_at: row.get(1)?,
-                };
-                Ok(link)
-            })
-            .optional()?
-        };
-        Ok(links)
-    }
-
-    #[tracing::instrument(skip(self))]
-    pub fn reverse_lookup(
-        &self,
-        namespace: String,
-        long_form: String,
-    ) -> anyhow::Result<Vec<Link>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = {
-            let _span = info_span!("prepare_statement").entered();
-            conn.prepare("SELECT short_form, long_form, created_at FROM links WHERE namespace = ? AND long_form = ?")?
-        };
-        let links: Vec<Link> = {
-            let _span = info_span!("query_map").entered();
-            stmt.query_map([namespace, long_form], |row| {
-                let link: Link = Link {
-                    short_form: row.get(0)?,
-                    long_form: row.get(1)?,
-                    created_at: row.get(2)?,
-                };
-                Ok(link)
-            })?
-            .collect::<Result<Vec<_>, _>>()?
-        };
-        Ok(links)
-    }
-
-    #[tracing::instrument(skip(self, link))]
-    pub fn create_link(&self, namespace: String, link: Link) -> anyhow::Result<()> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = {
-            let _span = info_span!("prepare_statement").entered();
-            conn.prepare(
-                "
-                INSERT INTO links (namespace, short_form, long_form, created_at)
-                VALUES (?, ?, ?, ?)
-                ON CONFLICT (namespace, short_form)
-                DO UPDATE SET
-                    long_form = excluded.long_form,
-                    created_at = excluded.created_at
-            ",
-            )?
-        };
-        info_span!("execute").in_scope(|| {
-            stmt.execute((namespace, link.short_form, link.long_form, link.created_at))
-        })?;
-        self.dirty.notify_one();
-        Ok(())
     }
+    Ok(())
 }
 
-type AppResult<T> = Result<T, AppError>;
-struct AppError(anyhow::Error);
-impl IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "msg": self.0.to_string() })),
-        )
-            .into_response()
-    }
-}
-impl From<anyhow::Error> for AppError {
-    fn from(value: anyhow::Error) -> Self {
-        Self(value)
-    }
+/// Logs to stdout in `--log-format`, and exports spans to `--otlp-endpoint` if there is one. The
+/// returned provider must be shut down before exiting so that buffered spans aren't lost.
+fn init_tracing(args: &Args) -> anyhow::Result<Option<TracerProvider>> {
+    let fmt_layer = match args.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_span_events(FmtSpan::CLOSE)
+            .boxed(),
+        // Include the enclosing spans so their fields (namespace, short_form, ...) are queryable
+        // on every event, not just on the span-close lines.
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_span_events(FmtSpan::CLOSE)
+            .boxed(),
+    };
+    let provider = match &args.otlp_endpoint {
+        Some(endpoint) => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .build()?;
+            let provider = TracerProvider::builder()
+                .with_batch_exporter(exporter, runtime::Tokio)
+                .with_resource(Resource::new([KeyValue::new("service.name", "flylinks")]))
+                .build();
+            Some(provider)
+        }
+        None => None,
+    };
+    let otel_layer = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("flylinks")));
+    tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(log_filter(args.lookup_log_level)))
+        .with(otel_layer.with_filter(LevelFilter::INFO))
+        .init();
+    Ok(provider)
 }
 
-#[derive(Serialize)]
-struct Link {
-    short_form: String,
-    long_form: String,
-    created_at: chrono::DateTime<Utc>,
-}
-#[derive(Serialize)]
-struct ListLinksResponse {
-    links: Vec<Link>,
-}
-async fn list_links(
-    State(state): State<ServerState>,
-    Path(namespace): Path<String>,
-) -> AppResult<Json<ListLinksResponse>> {
-    let links = state.list_links(namespace)?;
-    Ok(Json(ListLinksResponse { links }))
+/// Logs at info, except while handling a redirect or resolve (including the span-close lines for
+/// the request and everything under it), which is logged at `lookup_level`.
+fn log_filter<S>(lookup_level: LevelFilter) -> impl tracing_subscriber::layer::Filter<S> {
+    // Dynamic, so that it's asked about every span and event rather than once per callsite.
+    dynamic_filter_fn(move |metadata, _| {
+        let max = if handling_lookup() {
+            lookup_level
+        } else {
+            LevelFilter::INFO
+        };
+        *metadata.level() <= max
+    })
+    // So that finer callsites than either level are skipped without asking.
+    .with_max_level_hint(lookup_level.max(LevelFilter::INFO))
 }
 
-#[derive(Deserialize)]
-struct CreateLinkRequest {
-    short_form: String,
-    long_form: String,
-}
-#[derive(Serialize)]
-struct CreateLinkResponse {}
-async fn create_link(
-    State(state): State<ServerState>,
-    Path(namespace): Path<String>,
-    Json(request): Json<CreateLinkRequest>,
-) -> AppResult<Json<CreateLinkResponse>> {
-    state.create_link(
-        namespace,
-        Link {
-            short_form: request.short_form,
-            long_form: request.long_form,
-            created_at: chrono::Utc::now(),
-        },
-    )?;
-    Ok(Json(CreateLinkResponse {}))
+/// Re-reads the certificate and key whenever the process gets a SIGHUP, so that a renewed cert
+/// can be swapped in without dropping connections. A bad pair is logged and the old one kept.
+async fn reload_tls_on_sighup(tls: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    let mut hangups = signal(SignalKind::hangup()).expect("install SIGHUP handler");
+    while hangups.recv().await.is_some() {
+        match tls.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => info!(?cert, "reloaded TLS certificate"),
+            Err(err) => warn!(
+                ?err,
+                ?cert,
+                "failed to reload TLS certificate, keeping the old one"
+            ),
+        }
+    }
 }
 
-async fn get_link(
-    State(state): State<ServerState>,
-    Path((namespace, short_form)): Path<(String, String)>,
-) -> AppResult<Json<Link>> {
-    let Some(link) = state.get_link(namespace.clone(), short_form.clone())? else {
-        return Err(anyhow!("no link {namespace}/{short_form}").into());
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("install ctrl-c handler");
     };
-    Ok(Json(link))
-}
-
-async fn redirect_link(
-    State(state): State<ServerState>,
-    Path((namespace, short_form)): Path<(String, String)>,
-) -> AppResult<Response> {
-    let Some(link) = state.get_link(namespace.clone(), short_form.clone())? else {
-        return Ok(format!("no link for {namespace}/{short_form}").into_response());
+    let terminate = async {
+        signal(SignalKind::terminate())
+            .expect("install SIGTERM handler")
+            .recv()
+            .await;
     };
-    Ok(Redirect::temporary(&link.long_form).into_response())
-}
-
-#[derive(Deserialize)]
-struct ReverseLookupRequest {
-    long_form: String,
-}
-#[derive(Serialize)]
-struct ReverseLookupResponse {
-    links: Vec<Link>,
-}
-async fn reverse_lookup(
-    State(state): State<ServerState>,
-    Path(namespace): Path<String>,
-    Json(ReverseLookupRequest { long_form }): Json<ReverseLookupRequest>,
-) -> AppResult<Json<ReverseLookupResponse>> {
-    let links = state.reverse_lookup(namespace.clone(), long_form.clone())?;
-    Ok(Json(ReverseLookupResponse { links }))
+    tokio::select! {
+        _ = ctrl_c => info!("received ctrl-c"),
+        _ = terminate => info!("received SIGTERM"),
+    }
 }
 
+/// Every flag can also be set with the environment variable named in its help, like
+/// FLYLINKS_DB_PATH. A flag given on the command line wins over the environment.
 #[derive(Parser)]
 struct Args {
-    #[arg(long, default_value = "[::]:8080")]
+    #[arg(long, env = "FLYLINKS_ADDRESS", default_value = "[::]:8080")]
     address: String,
 
-    #[arg(long)]
-    s3_bucket: String,
+    #[arg(
+        long,
+        env = "FLYLINKS_TLS_CERT",
+        requires = "tls_key",
+        help = "Serve HTTPS with this PEM certificate (chain); reloaded on SIGHUP"
+    )]
+    tls_cert: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_TLS_KEY",
+        requires = "tls_cert",
+        help = "The PEM private key for --tls-cert"
+    )]
+    tls_key: Option<PathBuf>,
 
-    #[arg(long)]
-    s3_region: String,
+    #[command(flatten)]
+    store: StoreArgs,
 
-    #[arg(long)]
+    #[arg(long, env = "FLYLINKS_S3_PATH")]
     s3_path: String,
 
-    #[arg(long)]
+    #[arg(long, env = "FLYLINKS_DB_PATH")]
     db_path: PathBuf,
 
-    #[arg(long, help = "Where on disk to stage the backup db")]
-    backup_staging_path: PathBuf,
+    #[arg(
+        long,
+        env = "FLYLINKS_BACKUP_STAGING_DIR",
+        help = "Directory to stage each backup db in (defaults to the system temp dir)"
+    )]
+    backup_staging_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_BACKUP_ENCRYPTION_KEY_FILE",
+        help = "Encrypt backups with the base64 AES-256 key in this file (e.g. from `openssl rand -base64 32`); restores need the same key"
+    )]
+    backup_encryption_key_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_BACKUP_QUORUM",
+        help = "How many --backup-mirror stores a backup must reach to succeed, besides the main store, which it always must; the rest are logged as failures (all of them by default)"
+    )]
+    backup_quorum: Option<usize>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_RESTORE_ATTEMPTS",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "How many times to try downloading the backup on startup while the store is failing, backing off exponentially between attempts"
+    )]
+    restore_attempts: u32,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_RESTORE_DEADLINE_SECS",
+        help = "Stop retrying the startup download after this many seconds, even with attempts left"
+    )]
+    restore_deadline_secs: Option<u64>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_MAX_BACKUP_AGE_SECS",
+        help = "Back up at least this often, in seconds, even if nothing has been written"
+    )]
+    max_backup_age_secs: Option<u64>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_READ_ONLY",
+        help = "Serve a read-only replica: refuse writes, never back up, and periodically re-download the db"
+    )]
+    read_only: bool,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_REFRESH_INTERVAL_SECS",
+        default_value_t = 60,
+        help = "With --read-only, how often to re-download the db, in seconds"
+    )]
+    refresh_interval_secs: u64,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_AUTO_MIGRATE",
+        conflicts_with = "read_only",
+        help = "Apply any pending schema migrations on startup, starting from an empty db if there's no backup yet"
+    )]
+    auto_migrate: bool,
+
+    #[arg(
+        long = "case-insensitive-namespace",
+        env = "FLYLINKS_CASE_INSENSITIVE_NAMESPACES",
+        value_delimiter = ',',
+        help = "Match short_forms in this namespace ignoring case (repeatable)"
+    )]
+    case_insensitive_namespaces: Vec<String>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_MAX_LINKS_PER_NAMESPACE",
+        help = "The most live links any one namespace can hold (unlimited by default)"
+    )]
+    max_links_per_namespace: Option<usize>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_MAX_NAMESPACES",
+        help = "The most namespaces there can be; creates that would start another are refused, while existing ones stay writable (unlimited by default)"
+    )]
+    max_namespaces: Option<usize>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_LINK_CACHE_SIZE",
+        help = "Keep this many recently redirected links in memory, sparing hot links a db query (off by default)"
+    )]
+    link_cache_size: Option<NonZeroUsize>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_SQLITE_SYNCHRONOUS",
+        value_enum,
+        default_value_t = Synchronous::Full,
+        help = "PRAGMA synchronous. `normal` can lose the latest writes (never the last backup) to a power failure or OS crash; `off` can also corrupt the db, losing everything since the last backup"
+    )]
+    sqlite_synchronous: Synchronous,
 
-    #[arg(long, help = "should we read .env?")]
+    #[arg(
+        long,
+        env = "FLYLINKS_SQLITE_CACHE_SIZE",
+        allow_negative_numbers = true,
+        help = "PRAGMA cache_size: pages if positive, KiB if negative (SQLite's default of -2000 if unset)"
+    )]
+    sqlite_cache_size: Option<i64>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_SQLITE_TEMP_STORE",
+        value_enum,
+        default_value_t = TempStore::Default,
+        help = "PRAGMA temp_store: where temporary tables and indices go"
+    )]
+    sqlite_temp_store: TempStore,
+
+    #[arg(long, env = "FLYLINKS_DOTENV", help = "should we read .env?")]
     dotenv: bool,
+
+    #[arg(long, env = "FLYLINKS_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_LOOKUP_LOG_LEVEL",
+        default_value_t = LevelFilter::INFO,
+        help = "Log redirects and resolves at this level (off, error, warn, info, debug or trace), leaving everything else at info; `warn` keeps their errors but drops the line per request"
+    )]
+    lookup_log_level: LevelFilter,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_OTLP_ENDPOINT",
+        help = "Export spans to this OpenTelemetry collector (OTLP over gRPC), e.g. http://localhost:4317"
+    )]
+    otlp_endpoint: Option<String>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_REDACT_LOGGED_QUERIES",
+        help = "Strip query strings from the destinations in redirect access logs"
+    )]
+    redact_logged_queries: bool,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_COUNT_HEAD_CLICKS",
+        help = "Count HEAD requests to redirects as clicks (by default only GETs count, since HEADs are mostly link checkers)"
+    )]
+    count_head_clicks: bool,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_ALLOW_CREATED_AT",
+        help = "Let creates set created_at, and allow imports (which keep their links' timestamps)"
+    )]
+    allow_created_at: bool,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_ROOT_REDIRECT",
+        help = "Redirect GET / here (e.g. to a landing page) instead of describing the service"
+    )]
+    root_redirect: Option<String>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_KEEP_TRAILING_SLASHES",
+        help = "Route paths with trailing slashes as they are, rather than as though the slashes weren't there"
+    )]
+    keep_trailing_slashes: bool,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_FALLBACK_URL",
+        help = "Redirect unknown short_forms here, with ?ns=...&slug=... appended"
+    )]
+    fallback_url: Option<String>,
+
+    #[arg(
+        long = "namespace-fallback-url",
+        env = "FLYLINKS_NAMESPACE_FALLBACK_URLS",
+        value_name = "NAMESPACE=URL",
+        value_parser = parse_key_value,
+        help = "Like --fallback-url, for one namespace (repeatable)"
+    )]
+    namespace_fallback_urls: Vec<(String, String)>,
+
+    #[arg(
+        long = "allowed-origin",
+        env = "FLYLINKS_ALLOWED_ORIGINS",
+        value_delimiter = ',',
+        value_parser = |s: &str| HeaderValue::from_str(s),
+        help = "Let browser apps on this origin, e.g. https://admin.example.com, call the API (repeatable)"
+    )]
+    allowed_origins: Vec<HeaderValue>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_ROUTE_PREFIX",
+        default_value = "",
+        value_parser = parse_route_prefix,
+        help = "Serve every route under this path, e.g. /shortener"
+    )]
+    route_prefix: String,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_PUBLIC_BASE_URL",
+        help = "The externally visible URL of --route-prefix, e.g. https://go.example.com/shortener, for URLs in responses"
+    )]
+    public_base_url: Option<String>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_SLUG_BLOCKLIST",
+        help = "File of words (one per line) that generated short_forms must not contain, replacing the built-in list"
+    )]
+    slug_blocklist: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_SLUG_ALPHABET",
+        help = "Characters for generated short_forms, or \"unambiguous\" for lowercase letters and digits without 0/o/1/l/i"
+    )]
+    slug_alphabet: Option<String>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_SLUG_LENGTH",
+        default_value_t = 6,
+        help = "How many characters generated short_forms have"
+    )]
+    slug_length: usize,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_BASIC_AUTH",
+        value_name = "USER:PASSWORD",
+        help = "Require these HTTP Basic credentials for everything but redirects"
+    )]
+    basic_auth: Option<BasicAuth>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_ADMIN_AUTH",
+        value_name = "USER:PASSWORD",
+        help = "Require these HTTP Basic credentials for /v1/admin/* (backups, reloads, purges), which aren't served at all without them"
+    )]
+    admin_auth: Option<BasicAuth>,
+
+    #[arg(
+        long = "allowed-host",
+        env = "FLYLINKS_ALLOWED_HOSTS",
+        value_delimiter = ',',
+        help = "Only allow long_forms pointing at this host, or *.domain for its subdomains (repeatable)"
+    )]
+    allowed_hosts: Vec<String>,
+
+    #[arg(
+        long = "blocked-host",
+        env = "FLYLINKS_BLOCKED_HOSTS",
+        value_delimiter = ',',
+        help = "Refuse long_forms pointing at this host, or *.domain for its subdomains (repeatable)"
+    )]
+    blocked_hosts: Vec<String>,
+
+    #[arg(
+        long = "allowed-namespace",
+        env = "FLYLINKS_ALLOWED_NAMESPACES",
+        value_delimiter = ',',
+        help = "Only serve this namespace, answering 404 for any other (repeatable; by default any namespace can be used)"
+    )]
+    allowed_namespaces: Vec<String>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_IDEMPOTENCY_WINDOW_SECS",
+        default_value_t = 60 * 60,
+        help = "How long to remember a create's Idempotency-Key, in seconds"
+    )]
+    idempotency_window_secs: u64,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_MAX_SHORT_FORM_LEN",
+        default_value_t = 128,
+        help = "Longest short_form that can be created, in bytes"
+    )]
+    max_short_form_len: usize,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_SHORT_FORM_PATTERN",
+        help = "Regex that every client-chosen short_form must match in full, like '[a-z][a-z0-9]*(-[a-z0-9]+)*'. Generated short_forms are exempt"
+    )]
+    short_form_pattern: Option<String>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_MAX_LONG_FORM_LEN",
+        default_value_t = 2048,
+        help = "Longest long_form that can be stored, in bytes"
+    )]
+    max_long_form_len: usize,
+
+    #[arg(long, env = "FLYLINKS_READ_RATE_LIMIT", default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..), help = "Sustained requests per second per client IP for redirects and lookups")]
+    read_rate_limit: u32,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_READ_BURST",
+        default_value_t = 100,
+        help = "How many redirects and lookups a client IP may burst above the sustained rate"
+    )]
+    read_burst: u32,
+
+    #[arg(long, env = "FLYLINKS_WRITE_RATE_LIMIT", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), help = "Sustained requests per second per client IP for creates and updates")]
+    write_rate_limit: u32,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_WRITE_BURST",
+        default_value_t = 10,
+        help = "How many writes a client IP may burst above the sustained rate"
+    )]
+    write_burst: u32,
+
+    #[arg(long, env = "FLYLINKS_NAMESPACE_WRITE_RATE_LIMIT", default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..), help = "Sustained writes per second to any one namespace, from all clients together")]
+    namespace_write_rate_limit: u32,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_NAMESPACE_WRITE_BURST",
+        default_value_t = 100,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "How many writes a namespace may burst above the sustained rate"
+    )]
+    namespace_write_burst: u32,
+
+    #[arg(
+        long = "trusted-proxy",
+        env = "FLYLINKS_TRUSTED_PROXIES",
+        value_delimiter = ',',
+        value_name = "IP_OR_CIDR",
+        value_parser = parse_ip_net,
+        help = "Believe X-Forwarded-For from this proxy, like a load balancer, when rate limiting and logging clients (repeatable). Without one, clients are known by their own address"
+    )]
+    trusted_proxies: Vec<IpNet>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_MAX_BODY_BYTES",
+        default_value_t = 16 * 1024,
+        help = "Largest request body accepted for single-link requests, in bytes"
+    )]
+    max_body_bytes: usize,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_MAX_BATCH_BODY_BYTES",
+        default_value_t = 1024 * 1024,
+        help = "Largest request body accepted for batch requests, in bytes"
+    )]
+    max_batch_body_bytes: usize,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_REQUEST_TIMEOUT_SECS",
+        default_value_t = 10,
+        help = "How long a request may take, including sending its body, in seconds"
+    )]
+    request_timeout_secs: u64,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_MAX_CONCURRENT_REQUESTS",
+        help = "Turn away requests with a 503 while this many are already in flight (unlimited by default)"
+    )]
+    max_concurrent_requests: Option<usize>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_VERIFY_DESTINATIONS",
+        value_enum,
+        help = "Check that a long_form answers a HEAD before creating its link, and warn about (or reject) ones that don't. Off by default, since it adds latency and makes outbound requests"
+    )]
+    verify_destinations: Option<VerifyDestinations>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_VERIFY_DESTINATIONS_TIMEOUT_SECS",
+        default_value_t = 5,
+        help = "How long --verify-destinations waits for an answer, redirects included, in seconds"
+    )]
+    verify_destinations_timeout_secs: u64,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_VERIFY_PRIVATE_DESTINATIONS",
+        help = "Let --verify-destinations contact loopback, private and link-local addresses, for links that point inside a private network. Any client that can create links can then probe that network"
+    )]
+    verify_private_destinations: bool,
+}
+
+/// A CIDR block, or a single address.
+fn parse_ip_net(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("expected an IP address or CIDR block, got {s:?}"))
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got {s:?}"))?;
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// Normalizes to a leading slash and no trailing one, with "" (rather than "/") for the root.
+fn parse_route_prefix(s: &str) -> Result<String, String> {
+    let trimmed = s.trim_matches('/');
+    if trimmed.is_empty() {
+        return Ok(String::new());
+    }
+    if trimmed.contains(['*', ':']) {
+        return Err(format!("route prefix can't contain a wildcard, got {s:?}"));
+    }
+    Ok(format!("/{trimmed}"))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum VerifyDestinations {
+    /// Create the link anyway, saying what went wrong in the response.
+    Warn,
+    /// Turn the create away with a 422.
+    Reject,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines, for local development.
+    Text,
+    /// Newline-delimited JSON, for log aggregation pipelines.
+    Json,
 }
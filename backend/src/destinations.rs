@@ -0,0 +1,215 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect, StatusCode, Url,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// As many as browsers follow, give or take.
+const MAX_REDIRECTS: usize = 10;
+
+/// Asks a long_form's host whether it's there before the link is created, so that a typo'd or
+/// dead URL is caught by whoever makes the link rather than whoever clicks it months later. Every
+/// check is an outbound request to a URL a client picked, and adds its latency to the create.
+///
+/// Unless told otherwise, only public addresses are ever contacted, at every redirect, so that a
+/// client can't use the check to probe the server's own network (or a cloud metadata endpoint).
+#[derive(Debug)]
+pub struct DestinationVerifier {
+    client: reqwest::Client,
+    /// Turn away creates whose destination fails, rather than just reporting it.
+    reject: bool,
+    private_addresses: bool,
+}
+
+/// What a long_form's host said, as reported in the create response.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, ToSchema)]
+pub struct DestinationCheck {
+    /// Answered, and not with a 4xx or 5xx.
+    pub ok: bool,
+    /// After following redirects. Missing if the host couldn't be reached at all.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Why the host couldn't be reached, like a DNS failure, a timeout or a private address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl DestinationVerifier {
+    /// `timeout` covers the whole check, redirects included. `private_addresses` lets it contact
+    /// loopback, private and link-local addresses too, for links that point inside a private
+    /// network.
+    pub fn new(timeout: Duration, reject: bool, private_addresses: bool) -> anyhow::Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(timeout)
+            .user_agent(concat!("flylinks/", env!("CARGO_PKG_VERSION")));
+        if !private_addresses {
+            builder = builder
+                // A proxy would resolve the host itself, out of the resolver's sight.
+                .no_proxy()
+                .dns_resolver(Arc::new(PublicResolver))
+                .redirect(redirect::Policy::custom(|attempt| {
+                    if attempt.previous().len() >= MAX_REDIRECTS {
+                        attempt.error("too many redirects")
+                    } else if let Err(err) = check_literal_host(attempt.url()) {
+                        attempt.error(err)
+                    } else {
+                        attempt.follow()
+                    }
+                }));
+        }
+        let client = builder.build().context("build destination client")?;
+        Ok(Self {
+            client,
+            reject,
+            private_addresses,
+        })
+    }
+
+    pub fn rejects(&self) -> bool {
+        self.reject
+    }
+
+    pub async fn check(&self, long_form: &str) -> DestinationCheck {
+        let unreachable = |err: anyhow::Error| DestinationCheck {
+            ok: false,
+            status: None,
+            error: Some(format!("{err:#}")),
+        };
+        // Hosts that are already addresses never reach the resolver.
+        if !self.private_addresses {
+            let literal = Url::parse(long_form)
+                .map_err(anyhow::Error::from)
+                .and_then(|url| check_literal_host(&url));
+            if let Err(err) = literal {
+                return unreachable(err);
+            }
+        }
+        let response = match self.client.head(long_form).send().await {
+            // Some servers don't do HEAD at all. The GET's body is never read.
+            Ok(response) if response.status() == StatusCode::METHOD_NOT_ALLOWED => {
+                self.client.get(long_form).send().await
+            }
+            other => other,
+        };
+        match response {
+            Ok(response) => {
+                let status = response.status();
+                DestinationCheck {
+                    ok: !status.is_client_error() && !status.is_server_error(),
+                    status: Some(status.as_u16()),
+                    error: None,
+                }
+            }
+            // The URL is the client's own, and may have secrets in its query.
+            Err(err) => unreachable(err.without_url().into()),
+        }
+    }
+}
+
+/// Resolves hosts like the system does, but only to public addresses.
+struct PublicResolver;
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_owned();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(anyhow!("{host} has no public address").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn check_literal_host(url: &Url) -> anyhow::Result<()> {
+    let ip = match url.host() {
+        Some(url::Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(url::Host::Ipv6(ip)) => IpAddr::V6(ip),
+        _ => return Ok(()),
+    };
+    anyhow::ensure!(is_public(ip), "{ip} isn't a public address");
+    Ok(())
+}
+
+/// Whether `ip` is reachable on the public internet, as far as the special-purpose registries go.
+/// `IpAddr::is_global` would do, once it's stable.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network", shared address space (carrier-grade NAT), IETF protocol assignments,
+        // benchmarking, and reserved.
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, link-local, documentation, and NAT64 (which can reach IPv4 addresses).
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+        || (segments[0] == 0x64 && segments[1] == 0xff9b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_are_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["93.184.216.34", "1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
+
+use aes_gcm::{
+    aead::stream::{DecryptorBE32, EncryptorBE32},
+    Aes256Gcm, Key, KeyInit,
+};
+use anyhow::{anyhow, bail, Context};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use rand::RngCore;
+
+// Starts every encrypted backup. A plain one starts with "SQLite format 3" instead.
+const MAGIC: &[u8; 8] = b"FLYENC01";
+// Each chunk is sealed separately, so neither side ever holds the whole db in memory.
+const CHUNK_SIZE: usize = 1024 * 1024;
+const TAG_SIZE: usize = 16;
+// The rest of each chunk's 12-byte nonce is its index and whether it's the last one.
+const NONCE_PREFIX_SIZE: usize = 7;
+
+/// A customer-managed AES-256 key that backups are encrypted with before they leave the machine.
+#[derive(Clone)]
+pub struct BackupKey(Key<Aes256Gcm>);
+
+impl BackupKey {
+    /// Reads a key file holding 32 random bytes in base64, like `openssl rand -base64 32` makes.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("read backup key {}", path.display()))?;
+        let bytes = BASE64_STANDARD
+            .decode(contents.trim())
+            .context("backup key isn't base64")?;
+        if bytes.len() != 32 {
+            bail!("backup key is {} bytes, not 32", bytes.len());
+        }
+        Ok(Self(*Key::<Aes256Gcm>::from_slice(&bytes)))
+    }
+}
+
+// Keeps the key out of logs and panics.
+impl std::fmt::Debug for BackupKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BackupKey(..)")
+    }
+}
+
+/// Whether `header`, the start of a backup, says it's encrypted.
+pub fn is_encrypted(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+/// Writes `src` to `dst` as AES-256-GCM, in chunks, after a header holding the nonce prefix.
+pub fn encrypt(key: &BackupKey, mut src: impl Read, mut dst: impl Write) -> anyhow::Result<()> {
+    let mut nonce_prefix = [0; NONCE_PREFIX_SIZE];
+    rand::thread_rng().fill_bytes(&mut nonce_prefix);
+    dst.write_all(MAGIC)?;
+    dst.write_all(&nonce_prefix)?;
+    let mut encryptor = EncryptorBE32::from_aead(Aes256Gcm::new(&key.0), &nonce_prefix.into());
+    let mut chunk = read_chunk(&mut src, CHUNK_SIZE)?;
+    loop {
+        let next = read_chunk(&mut src, CHUNK_SIZE)?;
+        // Sealing the last chunk differently means truncation can't go unnoticed.
+        if next.is_empty() {
+            let sealed = encryptor
+                .encrypt_last(chunk.as_slice())
+                .map_err(|_| anyhow!("encrypt backup"))?;
+            dst.write_all(&sealed)?;
+            return Ok(());
+        }
+        let sealed = encryptor
+            .encrypt_next(chunk.as_slice())
+            .map_err(|_| anyhow!("encrypt backup"))?;
+        dst.write_all(&sealed)?;
+        chunk = next;
+    }
+}
+
+/// Reverses `encrypt`, failing if `key` isn't the one it was encrypted with or anything has been
+/// tampered with.
+pub fn decrypt(key: &BackupKey, mut src: impl Read, mut dst: impl Write) -> anyhow::Result<()> {
+    let mut magic = [0; MAGIC.len()];
+    let mut nonce_prefix = [0; NONCE_PREFIX_SIZE];
+    src.read_exact(&mut magic)
+        .and_then(|()| src.read_exact(&mut nonce_prefix))
+        .context("read encrypted backup header")?;
+    if !is_encrypted(&magic) {
+        bail!("backup isn't encrypted");
+    }
+    let mut decryptor = DecryptorBE32::from_aead(Aes256Gcm::new(&key.0), &nonce_prefix.into());
+    let wrong_key = || anyhow!("couldn't decrypt backup: wrong key, or the backup is corrupt");
+    let mut chunk = read_chunk(&mut src, CHUNK_SIZE + TAG_SIZE)?;
+    loop {
+        let next = read_chunk(&mut src, CHUNK_SIZE + TAG_SIZE)?;
+        if next.is_empty() {
+            let opened = decryptor
+                .decrypt_last(chunk.as_slice())
+                .map_err(|_| wrong_key())?;
+            dst.write_all(&opened)?;
+            return Ok(());
+        }
+        let opened = decryptor
+            .decrypt_next(chunk.as_slice())
+            .map_err(|_| wrong_key())?;
+        dst.write_all(&opened)?;
+        chunk = next;
+    }
+}
+
+/// Up to `len` bytes, only fewer at the end of `src`.
+fn read_chunk(src: &mut impl Read, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(len);
+    src.take(len as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> BackupKey {
+        BackupKey(*Key::<Aes256Gcm>::from_slice(&[byte; 32]))
+    }
+
+    #[test]
+    fn round_trips_across_chunk_boundaries() {
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 2 * CHUNK_SIZE + 17] {
+            let plain: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut sealed = Vec::new();
+            encrypt(&key(1), plain.as_slice(), &mut sealed).unwrap();
+            assert!(is_encrypted(&sealed));
+
+            let mut opened = Vec::new();
+            decrypt(&key(1), sealed.as_slice(), &mut opened).unwrap();
+            assert_eq!(opened, plain, "len {len}");
+        }
+    }
+
+    #[test]
+    fn wrong_keys_and_truncation_are_caught() {
+        let plain = vec![7; CHUNK_SIZE + 100];
+        let mut sealed = Vec::new();
+        encrypt(&key(1), plain.as_slice(), &mut sealed).unwrap();
+
+        let err = decrypt(&key(2), sealed.as_slice(), Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("wrong key"), "{err}");
+        // Dropping the last chunk leaves a valid-looking one that wasn't sealed as the last.
+        let truncated = &sealed[..MAGIC.len() + NONCE_PREFIX_SIZE + CHUNK_SIZE + TAG_SIZE];
+        assert!(decrypt(&key(1), truncated, Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,7 @@
+pub mod api;
+pub mod destinations;
+pub mod encryption;
+pub mod persistence;
+pub mod schema;
+pub mod slugs;
+pub mod store;
@@ -0,0 +1,2376 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use futures::StreamExt;
+use lru::LruCache;
+use object_store::{
+    ObjectStore, PutMode, PutOptions, PutPayload, PutResult, UpdateVersion, WriteMultipart,
+};
+use rand::Rng;
+use rusqlite::{types::Type, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Notify,
+};
+use tracing::{error, info, info_span, warn};
+use utoipa::ToSchema;
+
+use crate::encryption::{self, BackupKey};
+
+pub struct Persistence {
+    cfg: Config,
+    conn: Mutex<rusqlite::Connection>,
+    store: Arc<dyn ObjectStore>,
+    dirty: Notify,
+    /// Held from staging a backup until it's uploaded; see `backup`.
+    backup_lock: tokio::sync::Mutex<()>,
+    /// The version of the backup this db was restored from or last backed up to, which the next
+    /// backup expects to replace. `None` means there shouldn't be a backup yet.
+    last_backup: Mutex<Option<UpdateVersion>>,
+    /// Set once the store turns out not to support conditional puts, so that's only logged once.
+    unconditional_puts: AtomicBool,
+    clock: Clock,
+    backup_status: Mutex<BackupStatus>,
+    /// `None` unless `cfg.link_cache_size` is set.
+    link_cache: Option<Mutex<LinkCache>>,
+    /// Also written by every backup; see `with_mirrors`.
+    mirrors: Vec<BackupMirror>,
+}
+/// Another store each backup is copied to, say a bucket in another region, so that losing the
+/// main store doesn't lose the data. Only the main store is ever restored from: to restore from a
+/// mirror, point the server at it.
+pub struct BackupMirror {
+    /// For logs, like the bucket name.
+    pub name: String,
+    pub store: Arc<dyn ObjectStore>,
+}
+/// Recently redirected links, so that hot ones skip the connection lock and the query. Any write
+/// clears the whole thing, rather than working out which keys (aliases, other casings, ...) it
+/// touched; writes are rare next to redirects.
+struct LinkCache {
+    /// Keyed by the namespace and short_form as requested, which may differ in case from the
+    /// stored link's.
+    links: LruCache<(String, String), Link>,
+    /// Bumped by every clear, so that a lookup racing a write can't cache what it read before it.
+    generation: u64,
+}
+/// Where the time written into links comes from. Tests swap it out with `Persistence::with_clock`.
+pub type Clock = Arc<dyn Fn() -> DateTime<Utc> + Send + Sync>;
+#[derive(Debug)]
+pub struct Config {
+    pub db_path: PathBuf,
+    /// Where each backup is staged in its own temp file. `None` means the system temp dir.
+    pub backup_staging_dir: Option<PathBuf>,
+    pub s3_path: String,
+    /// Never write to the db (or back it up); it's a replica of whatever the primary last backed up.
+    pub read_only: bool,
+    /// Namespaces whose short_forms are matched, and kept unique, ignoring ASCII case.
+    pub case_insensitive_namespaces: HashSet<String>,
+    /// Bring the db's schema up to date on open, starting from an empty db if there's no backup
+    /// yet. Without this, the schema is expected to already be in place.
+    pub auto_migrate: bool,
+    /// Encrypt backups with this before uploading them, and decrypt them with it on download.
+    pub backup_key: Option<BackupKey>,
+    /// The most live links (aliases included) any one namespace can hold. `None` means no limit.
+    pub max_links_per_namespace: Option<usize>,
+    /// The most namespaces (with live links) there can be; creates in any other namespace are
+    /// turned away once there are this many. `None` means no limit.
+    pub max_namespaces: Option<usize>,
+    /// How many links to keep in memory for redirects. `None` turns the cache off.
+    pub link_cache_size: Option<NonZeroUsize>,
+    /// Applied to every connection to the db.
+    pub pragmas: Pragmas,
+    /// How many of the mirrors a backup has to reach to count as done, on top of the main store,
+    /// which it always has to. `None` means all of them.
+    pub backup_quorum: Option<usize>,
+    /// How hard `open` tries to download the backup while the store is failing.
+    pub restore_retries: RestoreRetries,
+}
+/// Retries for the download on startup, with exponential backoff, so that a store outage
+/// outlasting the client's own retries doesn't crash-loop the server. A missing or unreadable
+/// backup isn't retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RestoreRetries {
+    /// Including the first.
+    pub max_attempts: u32,
+    /// Doubled after each failure, up to `MAX_RESTORE_BACKOFF`.
+    pub initial_backoff: Duration,
+    /// Stop retrying once another attempt would start this long after the first. `None` means
+    /// only `max_attempts` applies.
+    pub deadline: Option<Duration>,
+}
+impl Default for RestoreRetries {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            deadline: None,
+        }
+    }
+}
+const MAX_RESTORE_BACKOFF: Duration = Duration::from_secs(60);
+/// SQLite tuning. The defaults are SQLite's own, apart from `synchronous`, whose default of
+/// `Full` is spelled out.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Pragmas {
+    pub synchronous: Synchronous,
+    /// As `PRAGMA cache_size` takes it: pages if positive, KiB if negative. `None` leaves SQLite's
+    /// default (2000 KiB).
+    pub cache_size: Option<i64>,
+    pub temp_store: TempStore,
+}
+/// When SQLite waits for writes to reach the disk (`PRAGMA synchronous`). The db is in WAL mode, so:
+/// - `Full` (the default) makes every commit durable before it's acknowledged.
+/// - `Normal` can lose the last few commits to a power failure or OS crash (not a process crash),
+///   but never corrupts the db. Anything already backed up survives regardless.
+/// - `Off` can corrupt the db on a power failure or OS crash. Since the db is restored from the
+///   latest backup on every start, that costs whatever was written since the last backup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    #[default]
+    Full,
+}
+/// Where SQLite keeps temporary tables and indices (`PRAGMA temp_store`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TempStore {
+    /// Whatever SQLite was compiled to use, which is normally files.
+    #[default]
+    Default,
+    File,
+    Memory,
+}
+impl Persistence {
+    /// Restores the db from the most recent backup in `store` and opens it at `cfg.db_path`.
+    #[tracing::instrument(skip(store))]
+    pub async fn open(cfg: Config, store: Arc<dyn ObjectStore>) -> anyhow::Result<Self> {
+        let _ = std::fs::remove_file(&cfg.db_path);
+        let version = match download_with_retries(store.as_ref(), &cfg).await {
+            Ok(version) => Some(version),
+            Err(err) if cfg.auto_migrate && is_not_found(&err) => {
+                info!("no backup yet, starting from an empty db");
+                None
+            }
+            Err(err) => return Err(err),
+        };
+        let mut conn = connect(&cfg)?;
+        if cfg.auto_migrate {
+            crate::schema::ensure_schema(&mut conn)?;
+        }
+        let state = Self::new(cfg, conn, store);
+        state.set_last_backup(version);
+        Ok(state)
+    }
+
+    /// Replaces the db with the latest backup in the store, picking up whatever the primary has
+    /// written since. Only meaningful for a read-only replica: a writer would lose its own changes.
+    #[tracing::instrument(skip(self))]
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.cfg.read_only, "only read-only replicas can refresh");
+        self.reload().await?;
+        Ok(())
+    }
+
+    /// Replaces the db with the backup in the store, say after restoring an old one there by hand,
+    /// and returns how many links it has. On a writer, anything written since the last backup is
+    /// lost. The new db is checked before it's swapped in, so a bad backup leaves the old one in
+    /// place; requests wait for the swap rather than seeing it half done.
+    #[tracing::instrument(skip(self))]
+    pub async fn reload(&self) -> anyhow::Result<usize> {
+        let (tmp, version) = fetch(self.store.as_ref(), &self.cfg).await?;
+        let (tmp, links) = tokio::task::spawn_blocking(move || {
+            let conn = rusqlite::Connection::open_with_flags(
+                tmp.path(),
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+            )?;
+            let check: String = conn.query_row("PRAGMA quick_check", [], |row| row.get(0))?;
+            anyhow::ensure!(check == "ok", "downloaded db is corrupt: {check}");
+            let links: usize =
+                conn.query_row("SELECT COUNT(*) FROM links", [], |row| row.get(0))?;
+            anyhow::Ok((tmp, links))
+        })
+        .await?
+        .context("check downloaded db")?;
+        // Keeps a backup from uploading the old db over the one being swapped in.
+        let _guard = self.backup_lock.lock().await;
+        let mut conn = self.lock_conn();
+        // The old connection has to be closed before its file is replaced, or its WAL could be
+        // replayed into the new one.
+        *conn = rusqlite::Connection::open_in_memory()?;
+        let swapped = tmp
+            .persist(&self.cfg.db_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| {
+                let mut conn = connect(&self.cfg)?;
+                if self.cfg.auto_migrate && !self.cfg.read_only {
+                    crate::schema::ensure_schema(&mut conn)?;
+                }
+                Ok(conn)
+            });
+        match swapped {
+            Ok(new_conn) => *conn = new_conn,
+            Err(err) => {
+                // Back to whichever db is in place, which is the old one unless only the
+                // migration failed.
+                *conn = connect(&self.cfg)?;
+                return Err(err);
+            }
+        }
+        drop(conn);
+        self.clear_link_cache();
+        self.set_last_backup(Some(version));
+        info!(links, "swapped in reloaded db");
+        Ok(links)
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.cfg.read_only
+    }
+
+    /// Wraps an already-open db (e.g. an in-memory one) without restoring anything from `store`.
+    pub fn new(cfg: Config, conn: rusqlite::Connection, store: Arc<dyn ObjectStore>) -> Self {
+        let link_cache = cfg.link_cache_size.map(|size| {
+            Mutex::new(LinkCache {
+                links: LruCache::new(size),
+                generation: 0,
+            })
+        });
+        Self {
+            cfg,
+            conn: Mutex::new(conn),
+            store,
+            dirty: Notify::new(),
+            backup_lock: tokio::sync::Mutex::new(()),
+            last_backup: Mutex::new(None),
+            unconditional_puts: AtomicBool::new(false),
+            clock: Arc::new(Utc::now),
+            backup_status: Mutex::new(BackupStatus::default()),
+            link_cache,
+            mirrors: Vec::new(),
+        }
+    }
+
+    /// Uses `clock` in place of the system clock.
+    pub fn with_clock(self, clock: Clock) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Copies every backup to `mirrors` too, at the same `s3_path`. Unlike the main store's, their
+    /// backups are overwritten unconditionally: nothing is restored from them, so there's no
+    /// version to check against.
+    pub fn with_mirrors(self, mirrors: Vec<BackupMirror>) -> Self {
+        Self { mirrors, ..self }
+    }
+
+    /// What the clock says it is, for timestamping writes.
+    pub fn now(&self) -> DateTime<Utc> {
+        (self.clock)()
+    }
+
+    fn last_backup(&self) -> Option<UpdateVersion> {
+        self.last_backup
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn set_last_backup(&self, version: Option<UpdateVersion>) {
+        *self
+            .last_backup
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = version;
+    }
+
+    /// A panic while the lock was held (e.g. in a request handler) poisons it, but any transaction
+    /// it was in the middle of has been rolled back by then, so the connection is still usable.
+    /// Carrying on beats failing every request and backup from then on.
+    fn lock_conn(&self) -> MutexGuard<'_, rusqlite::Connection> {
+        self.conn.lock().unwrap_or_else(|poisoned| {
+            warn!("recovering connection lock poisoned by a panic");
+            self.conn.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    /// The clause matching `short_form` against the numbered parameter `param`, respecting the
+    /// namespace's case sensitivity. When several rows match, ordering by the returned tiebreak
+    /// prefers an exact match over a case-insensitive one.
+    fn short_form_match(&self, namespace: &str, param: usize) -> (String, String) {
+        if self.cfg.case_insensitive_namespaces.contains(namespace) {
+            (
+                format!("short_form = ?{param} COLLATE NOCASE"),
+                format!("ORDER BY short_form = ?{param} DESC"),
+            )
+        } else {
+            (format!("short_form = ?{param}"), String::new())
+        }
+    }
+
+    /// Resolves once a write has changed the db since the last call.
+    pub async fn wait_dirty(&self) {
+        self.dirty.notified().await
+    }
+
+    fn mark_dirty(&self) {
+        self.lock_backup_status().last_dirty_at = Some(self.now());
+        self.clear_link_cache();
+        self.dirty.notify_one();
+    }
+
+    fn clear_link_cache(&self) {
+        if let Some(cache) = &self.link_cache {
+            let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+            cache.links.clear();
+            cache.generation += 1;
+        }
+    }
+
+    fn lock_backup_status(&self) -> MutexGuard<'_, BackupStatus> {
+        self.backup_status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// How backups have been going, for alerting when they fall behind.
+    pub fn backup_status(&self) -> BackupStatus {
+        self.lock_backup_status().clone()
+    }
+
+    /// Stages and uploads a backup. Backups run one at a time, so that an older snapshot can never
+    /// finish uploading after, and overwrite, a newer one.
+    pub async fn backup(self: &Arc<Self>) -> anyhow::Result<BackupSummary> {
+        let _guard = self.backup_lock.lock().await;
+        let started = self.now();
+        let result = async {
+            // The checkpoint and the copy both block, for as long as the db takes to copy.
+            let staging = self.clone();
+            let staged = tokio::task::spawn_blocking(move || staging.stage_backup())
+                .await?
+                .context("stage backup")?;
+            self.backup_to_s3(staged).await.context("upload backup")
+        }
+        .await;
+        let mut status = self.lock_backup_status();
+        match &result {
+            Ok(summary) => {
+                status.last_backup_at = Some(started);
+                status.last_backup_size = Some(summary.size);
+                status.consecutive_failures = 0;
+            }
+            Err(_) => status.consecutive_failures += 1,
+        }
+        result
+    }
+
+    fn staging_file(&self) -> anyhow::Result<tempfile::NamedTempFile> {
+        match &self.cfg.backup_staging_dir {
+            Some(dir) => tempfile::NamedTempFile::new_in(dir),
+            None => tempfile::NamedTempFile::new(),
+        }
+        .context("create backup staging file")
+    }
+
+    /// Copies the db to a staging file as of the moment it's called. In WAL mode the copy is read
+    /// through a connection of its own, so requests only wait for the checkpoint, not the copy.
+    #[tracing::instrument(skip(self))]
+    pub fn stage_backup(&self) -> anyhow::Result<tempfile::NamedTempFile> {
+        // A fresh file per run, so overlapping backups can't clobber each other's staging db. It's
+        // deleted when `staging` drops.
+        let staging = self.staging_file()?;
+        let snapshot = {
+            let conn = self.lock_conn();
+            checkpoint_wal(&conn)?;
+            // Outside WAL mode a second connection's read would block writes anyway (and an
+            // in-memory db can't be opened twice), so the copy holds the lock instead.
+            if !in_wal_mode(&conn)? {
+                copy_db(&conn, staging.path())?;
+                return Ok(staging);
+            }
+            // Started while the lock is held, so the snapshot is exactly the db as checkpointed.
+            open_snapshot(&self.cfg.db_path)?
+        };
+        copy_db(&snapshot, staging.path())?;
+        Ok(staging)
+    }
+
+    /// Uploads a db staged by `stage_backup`. Large ones are streamed from disk in parts, so
+    /// memory use doesn't grow with the db. The store's client retries each request (and so each
+    /// part) on its own; a part that still fails aborts the whole upload, leaving the previous
+    /// backup in place.
+    ///
+    /// The upload only replaces the backup this db was restored from (or last backed up to). If
+    /// anything else has written there since, like a second server misconfigured with the same
+    /// `s3_path`, the backup fails instead of clobbering it.
+    ///
+    /// Mirrors are uploaded to once the main store has the backup: it's the only one restored from,
+    /// and the one that knows whether this db still owns the backup. The backup succeeds if it
+    /// also reaches `backup_quorum` of the mirrors, with the rest listed in the summary.
+    #[tracing::instrument(skip(self, staged))]
+    pub async fn backup_to_s3(
+        &self,
+        staged: tempfile::NamedTempFile,
+    ) -> anyhow::Result<BackupSummary> {
+        let path = self.cfg.s3_path.as_str().into();
+        let staged = match &self.cfg.backup_key {
+            Some(key) => {
+                let (key, encrypted) = (key.clone(), self.staging_file()?);
+                tokio::task::spawn_blocking(move || {
+                    let _span = info_span!("encrypt_backup").entered();
+                    encryption::encrypt(&key, std::fs::File::open(staged.path())?, &encrypted)?;
+                    anyhow::Ok(encrypted)
+                })
+                .await??
+            }
+            None => staged,
+        };
+        let size = tokio::fs::metadata(staged.path()).await?.len();
+        self.upload_to_main(&path, staged.path(), size).await?;
+        let mirrored = futures::future::join_all(self.mirrors.iter().map(|mirror| async {
+            let result = upload_to_mirror(mirror, &path, staged.path(), size).await;
+            match &result {
+                Ok(put_response) => {
+                    info!(
+                        mirror = mirror.name,
+                        ?put_response,
+                        "finished mirroring backup"
+                    )
+                }
+                Err(err) => error!(mirror = mirror.name, ?err, "failed to mirror backup"),
+            }
+            result.with_context(|| format!("mirror {}", mirror.name))
+        }))
+        .await;
+        let total = self.mirrors.len();
+        let required = self.cfg.backup_quorum.unwrap_or(total).min(total);
+        let mut failed = Vec::new();
+        let mut errors = Vec::new();
+        for (mirror, result) in self.mirrors.iter().zip(mirrored) {
+            if let Err(err) = result {
+                failed.push(mirror.name.clone());
+                errors.push(err);
+            }
+        }
+        let reached = total - failed.len();
+        if reached < required {
+            let err = errors.remove(0);
+            return Err(err.context(format!(
+                "backup reached {reached} of {total} mirrors, short of the {required} required"
+            )));
+        }
+        Ok(BackupSummary {
+            path: self.cfg.s3_path.clone(),
+            size,
+            failed,
+        })
+    }
+
+    /// Uploads to the main store, replacing only the backup this db last restored or backed up
+    /// (see `backup_to_s3`), and records the new version for next time.
+    async fn upload_to_main(
+        &self,
+        path: &object_store::path::Path,
+        staged: &std::path::Path,
+        size: u64,
+    ) -> anyhow::Result<()> {
+        let mut file = tokio::fs::File::open(staged).await?;
+        let expected = self.last_backup();
+        info!(size, ?expected, "uploading backup");
+        let put_response = if size <= UPLOAD_PART_SIZE as u64 {
+            let mut content = Vec::with_capacity(size as usize);
+            file.read_to_end(&mut content).await?;
+            self.put_conditionally(path, PutPayload::from(content), expected)
+                .await?
+        } else {
+            // Multipart uploads can't be conditional, so this only narrows the race to the length
+            // of the upload.
+            self.check_unchanged(path, expected).await?;
+            put_multipart(self.store.as_ref(), path, file).await?
+        };
+        info!(?put_response, "finished uploading backup");
+        self.set_last_backup(Some(UpdateVersion {
+            e_tag: put_response.e_tag,
+            version: put_response.version,
+        }));
+        Ok(())
+    }
+
+    /// Puts `payload` only if the object at `path` is still `expected` (or, for `None`, doesn't
+    /// exist), falling back to a plain put on stores that can't do that.
+    async fn put_conditionally(
+        &self,
+        path: &object_store::path::Path,
+        payload: PutPayload,
+        expected: Option<UpdateVersion>,
+    ) -> anyhow::Result<PutResult> {
+        let mode = match expected {
+            _ if self.unconditional_puts.load(Ordering::Relaxed) => PutMode::Overwrite,
+            Some(UpdateVersion {
+                e_tag: None,
+                version: None,
+            }) => PutMode::Overwrite,
+            Some(expected) => PutMode::Update(expected),
+            None => PutMode::Create,
+        };
+        let conditional = !matches!(mode, PutMode::Overwrite);
+        match self
+            .store
+            .put_opts(path, payload.clone(), PutOptions::from(mode))
+            .await
+        {
+            Ok(result) => Ok(result),
+            Err(object_store::Error::NotImplemented) if conditional => {
+                warn!("the store doesn't support conditional puts, so backups will overwrite whatever is there");
+                self.unconditional_puts.store(true, Ordering::Relaxed);
+                Ok(self.store.put(path, payload).await?)
+            }
+            Err(
+                err @ (object_store::Error::Precondition { .. }
+                | object_store::Error::AlreadyExists { .. }),
+            ) => Err(self.conflict(path, err.into())),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Fails if the object at `path` isn't `expected`, as `put_conditionally` would.
+    async fn check_unchanged(
+        &self,
+        path: &object_store::path::Path,
+        expected: Option<UpdateVersion>,
+    ) -> anyhow::Result<()> {
+        let current = match self.store.head(path).await {
+            Ok(meta) => Some(UpdateVersion {
+                e_tag: meta.e_tag,
+                version: meta.version,
+            }),
+            Err(object_store::Error::NotFound { .. }) => None,
+            Err(err) => return Err(err.into()),
+        };
+        if current != expected {
+            return Err(self.conflict(
+                path,
+                anyhow::anyhow!("expected {expected:?}, found {current:?}"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn conflict(&self, path: &object_store::path::Path, err: anyhow::Error) -> anyhow::Error {
+        error!(
+            %path,
+            ?err,
+            "backup was changed by another writer; not overwriting it. Is another server using the same s3_path?"
+        );
+        err.context(format!(
+            "{path} was changed by another writer since this db last restored or backed up"
+        ))
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn list_links(&self, namespace: String, filter: LinkFilter) -> anyhow::Result<Vec<Link>> {
+        let live = if filter.include_deleted {
+            ""
+        } else {
+            "AND deleted_at IS NULL"
+        };
+        let conn = self.lock_conn();
+        let mut stmt = {
+            let _span = info_span!("prepare_statement").entered();
+            conn.prepare(&format!(
+                "
+                SELECT {LINK_COLUMNS} FROM links
+                WHERE namespace = ?1 {live}
+                    AND (?2 IS NULL OR EXISTS (
+                        SELECT 1 FROM json_each(links.metadata, '$.tags') WHERE value = ?2
+                    ))
+                    AND (?3 IS NULL OR json_extract(metadata, '$.owner') = ?3)
+                "
+            ))?
+        };
+        let links: Vec<Link> = {
+            let _span = info_span!("query_map").entered();
+            stmt.query_map((namespace, filter.tag, filter.owner), Link::from_row)?
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        Ok(links)
+    }
+
+    /// When anything about the namespace's links (deleted ones included) last changed, or `None`
+    /// if it has never had any. Redirects bumping `last_accessed_at` only count with `accesses`.
+    #[tracing::instrument(skip(self))]
+    pub fn namespace_changed_at(
+        &self,
+        namespace: String,
+        accesses: bool,
+    ) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let conn = self.lock_conn();
+        Ok(conn.query_row(
+            "
+            SELECT MAX(at) FROM (
+                SELECT changed_at AS at FROM namespace_changes WHERE namespace = ?1
+                UNION ALL
+                SELECT accessed_at FROM namespace_accesses WHERE namespace = ?1 AND ?2
+            )
+            ",
+            (namespace, accesses),
+            |row| row.get(0),
+        )?)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn get_link(&self, namespace: String, short_form: String) -> anyhow::Result<Option<Link>> {
+        let conn = self.lock_conn();
+        Ok(self.find_link(&conn, &namespace, &short_form, false)?)
+    }
+
+    /// Like `get_link`, but answered from memory if the link was looked up recently and hasn't
+    /// been written since. Its `last_accessed_at` may be stale, which is fine for redirecting.
+    #[tracing::instrument(skip(self))]
+    pub fn get_link_cached(
+        &self,
+        namespace: String,
+        short_form: String,
+    ) -> anyhow::Result<Option<Link>> {
+        let Some(cache) = &self.link_cache else {
+            return self.get_link(namespace, short_form);
+        };
+        let lock_cache = || cache.lock().unwrap_or_else(PoisonError::into_inner);
+        let key = (namespace, short_form);
+        let generation = {
+            let mut cache = lock_cache();
+            if let Some(link) = cache.links.get(&key) {
+                return Ok(Some(link.clone()));
+            }
+            cache.generation
+        };
+        let link = self.get_link(key.0.clone(), key.1.clone())?;
+        if let Some(link) = &link {
+            let mut cache = lock_cache();
+            if cache.generation == generation {
+                cache.links.put(key, link.clone());
+            }
+        }
+        Ok(link)
+    }
+
+    /// The wildcard link (one whose short_form ends in `/*`) covering `short_form`, along with the
+    /// part of `short_form` the wildcard matched. When several cover it, the longest prefix wins,
+    /// so `jira/core/*` beats `jira/*` for `jira/core/123`. The wildcard never matches nothing.
+    #[tracing::instrument(skip(self))]
+    pub fn get_wildcard_link(
+        &self,
+        namespace: String,
+        short_form: String,
+    ) -> anyhow::Result<Option<(Link, String)>> {
+        let conn = self.lock_conn();
+        for (slash, _) in short_form.rmatch_indices('/') {
+            let suffix = &short_form[slash + 1..];
+            if suffix.is_empty() {
+                continue;
+            }
+            let pattern = format!("{}{WILDCARD_SUFFIX}", &short_form[..slash]);
+            if let Some(link) = self.find_link(&conn, &namespace, &pattern, false)? {
+                return Ok(Some((link, suffix.to_owned())));
+            }
+        }
+        Ok(None)
+    }
+
+    /// In a case-insensitive namespace this finds the link under any casing, preferring an exact
+    /// match. The returned link's short_form is always as stored. Deleted links are only found if
+    /// `include_deleted`.
+    fn find_link(
+        &self,
+        conn: &rusqlite::Connection,
+        namespace: &str,
+        short_form: &str,
+        include_deleted: bool,
+    ) -> rusqlite::Result<Option<Link>> {
+        let (matches, tiebreak) = self.short_form_match(namespace, 2);
+        let live = if include_deleted {
+            ""
+        } else {
+            "AND deleted_at IS NULL"
+        };
+        let mut stmt = {
+            let _span = info_span!("prepare_statement").entered();
+            conn.prepare_cached(&format!(
+                "SELECT {LINK_COLUMNS} FROM links WHERE namespace = ?1 AND {matches} {live} {tiebreak} LIMIT 1"
+            ))?
+        };
+        let _span = info_span!("query_row").entered();
+        stmt.query_row([namespace, short_form], Link::from_row)
+            .optional()
+    }
+
+    /// Links whose short_form starts with `prefix`, in lexical order. The prefix is matched as
+    /// case-insensitively as the namespace's short_forms are.
+    #[tracing::instrument(skip(self))]
+    pub fn search_links(
+        &self,
+        namespace: String,
+        prefix: String,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Link>> {
+        // LIKE ignores ASCII case, so case-sensitive namespaces need an exact check on top.
+        let exact = if self.cfg.case_insensitive_namespaces.contains(&namespace) {
+            ""
+        } else {
+            "AND substr(short_form, 1, length(?2)) = ?2"
+        };
+        let pattern = format!("{}%", escape_like(&prefix));
+        let conn = self.lock_conn();
+        let mut stmt = {
+            let _span = info_span!("prepare_statement").entered();
+            conn.prepare(&format!(
+                "
+                SELECT {LINK_COLUMNS} FROM links
+                WHERE namespace = ?1 AND short_form LIKE ?3 ESCAPE '\\' {exact}
+                    AND deleted_at IS NULL
+                ORDER BY short_form
+                LIMIT ?4
+                "
+            ))?
+        };
+        let links: Vec<Link> = {
+            let _span = info_span!("query_map").entered();
+            stmt.query_map((namespace, prefix, pattern, limit), Link::from_row)?
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        Ok(links)
+    }
+
+    /// A live, enabled link from `namespace` chosen uniformly at random, or `None` if it has none.
+    /// Wildcard links are left out, having no destination of their own.
+    ///
+    /// Counts the candidates and then skips a random number of them, both by walking the
+    /// namespace's part of the primary key index: linear in the size of the namespace (not the
+    /// table), but without the sort, and the `random()` per row, of `ORDER BY RANDOM()`.
+    #[tracing::instrument(skip(self))]
+    pub fn random_link(&self, namespace: String) -> anyhow::Result<Option<Link>> {
+        const CANDIDATES: &str =
+            "FROM links WHERE namespace = ?1 AND deleted_at IS NULL AND enabled AND short_form NOT LIKE '%/*'";
+        let conn = self.lock_conn();
+        let count: u64 = {
+            let _span = info_span!("count_links").entered();
+            conn.query_row(
+                &format!("SELECT COUNT(*) {CANDIDATES}"),
+                [&namespace],
+                |row| row.get(0),
+            )?
+        };
+        if count == 0 {
+            return Ok(None);
+        }
+        let offset = rand::thread_rng().gen_range(0..count);
+        let _span = info_span!("query_row").entered();
+        let link = conn
+            .query_row(
+                &format!(
+                    "SELECT {LINK_COLUMNS} {CANDIDATES} ORDER BY short_form LIMIT 1 OFFSET ?2"
+                ),
+                (&namespace, offset),
+                Link::from_row,
+            )
+            .optional()?;
+        Ok(link)
+    }
+
+    /// Up to `limit` namespaces with live links, in lexical order, starting after `after`.
+    #[tracing::instrument(skip(self))]
+    pub fn list_namespaces(
+        &self,
+        after: Option<String>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<NamespaceSummary>> {
+        let conn = self.lock_conn();
+        let mut stmt = {
+            let _span = info_span!("prepare_statement").entered();
+            conn.prepare_cached(
+                "
+                SELECT namespace, COUNT(*) FROM links
+                WHERE deleted_at IS NULL AND (?1 IS NULL OR namespace > ?1)
+                GROUP BY namespace
+                ORDER BY namespace
+                LIMIT ?2
+                ",
+            )?
+        };
+        let namespaces = {
+            let _span = info_span!("query_map").entered();
+            stmt.query_map((after, limit), |row| {
+                Ok(NamespaceSummary {
+                    namespace: row.get(0)?,
+                    links: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?
+        };
+        Ok(namespaces)
+    }
+
+    /// The live link named `short_form` in each namespace that has one, as `(namespace, link)` in
+    /// namespace order, starting after the namespace `after`. Case-insensitive namespaces match
+    /// it ignoring case, as their redirects do.
+    #[tracing::instrument(skip(self))]
+    pub fn links_by_short_form(
+        &self,
+        short_form: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(String, Link)>> {
+        // One placeholder per case-insensitive namespace, after the three fixed parameters.
+        let mut params: Vec<&dyn rusqlite::ToSql> = vec![&short_form, &after, &limit];
+        let case_insensitive = if self.cfg.case_insensitive_namespaces.is_empty() {
+            String::new()
+        } else {
+            let placeholders = (0..self.cfg.case_insensitive_namespaces.len())
+                .map(|idx| format!("?{}", idx + 4))
+                .collect::<Vec<_>>()
+                .join(", ");
+            params.extend(
+                self.cfg
+                    .case_insensitive_namespaces
+                    .iter()
+                    .map(|ns| ns as &dyn rusqlite::ToSql),
+            );
+            format!("OR (namespace IN ({placeholders}) AND short_form = ?1 COLLATE NOCASE)")
+        };
+        let conn = self.lock_conn();
+        let mut stmt = {
+            let _span = info_span!("prepare_statement").entered();
+            conn.prepare(&format!(
+                "
+                SELECT {LINK_COLUMNS}, namespace FROM links
+                WHERE (short_form = ?1 {case_insensitive})
+                    AND deleted_at IS NULL AND (?2 IS NULL OR namespace > ?2)
+                ORDER BY namespace
+                LIMIT ?3
+                "
+            ))?
+        };
+        let links = {
+            let _span = info_span!("query_map").entered();
+            stmt.query_map(params.as_slice(), |row| {
+                Ok((row.get(12)?, Link::from_row(row)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?
+        };
+        Ok(links)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn reverse_lookup(
+        &self,
+        namespace: String,
+        long_form: String,
+    ) -> anyhow::Result<Vec<Link>> {
+        let conn = self.lock_conn();
+        let mut stmt = {
+            let _span = info_span!("prepare_statement").entered();
+            conn.prepare(&format!(
+                "SELECT {LINK_COLUMNS} FROM links WHERE namespace = ? AND long_form = ? AND deleted_at IS NULL"
+            ))?
+        };
+        let links: Vec<Link> = {
+            let _span = info_span!("query_map").entered();
+            stmt.query_map([namespace, long_form], Link::from_row)?
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        Ok(links)
+    }
+
+    /// Like `reverse_lookup` for many long_forms at once, returning each one's links in the order
+    /// the long_forms were given.
+    #[tracing::instrument(skip(self, long_forms), fields(count = long_forms.len()))]
+    pub fn reverse_lookup_batch(
+        &self,
+        namespace: String,
+        long_forms: &[String],
+    ) -> anyhow::Result<Vec<Vec<Link>>> {
+        let distinct: Vec<&String> = long_forms
+            .iter()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let mut by_long_form: HashMap<String, Vec<Link>> = HashMap::new();
+        let conn = self.lock_conn();
+        for chunk in distinct.chunks(PARAM_CHUNK_SIZE) {
+            let placeholders = vec!["?"; chunk.len()].join(", ");
+            let mut stmt = {
+                let _span = info_span!("prepare_statement").entered();
+                conn.prepare_cached(&format!(
+                    "SELECT {LINK_COLUMNS} FROM links WHERE namespace = ? AND long_form IN ({placeholders}) AND deleted_at IS NULL"
+                ))?
+            };
+            let params = std::iter::once(&namespace).chain(chunk.iter().copied());
+            let _span = info_span!("query_map").entered();
+            for link in stmt.query_map(rusqlite::params_from_iter(params), Link::from_row)? {
+                let link = link?;
+                by_long_form
+                    .entry(link.long_form.clone())
+                    .or_default()
+                    .push(link);
+            }
+        }
+        Ok(long_forms
+            .iter()
+            .map(|long_form| by_long_form.get(long_form).cloned().unwrap_or_default())
+            .collect())
+    }
+
+    /// Counts a redirect through `short_form` (as stored) in the bucket for the hour containing `at`,
+    /// and bumps its `last_accessed_at`.
+    ///
+    /// This deliberately doesn't mark the db dirty: backing up on every redirect would be far too
+    /// expensive, so click counts are persisted along with the next write (or at shutdown).
+    #[tracing::instrument(skip(self))]
+    pub fn record_click(
+        &self,
+        namespace: String,
+        short_form: String,
+        at: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        // A replica's counts would be discarded by its next refresh anyway.
+        if self.cfg.read_only {
+            return Ok(());
+        }
+        let hour = at.duration_trunc(TimeDelta::hours(1))?;
+        let conn = self.lock_conn();
+        let mut stmt = {
+            let _span = info_span!("prepare_statement").entered();
+            conn.prepare_cached(
+                "
+                INSERT INTO link_clicks (namespace, short_form, hour, count) VALUES (?1, ?2, ?3, 1)
+                ON CONFLICT (namespace, short_form, hour) DO UPDATE SET count = count + 1
+                ",
+            )?
+        };
+        info_span!("execute").in_scope(|| stmt.execute((&namespace, &short_form, hour)))?;
+        // Only rewritten once per LAST_ACCESS_RESOLUTION, rather than on every hit to a busy link.
+        let mut stmt = {
+            let _span = info_span!("prepare_statement").entered();
+            conn.prepare_cached(
+                "
+                UPDATE links SET last_accessed_at = ?3
+                WHERE namespace = ?1 AND short_form = ?2
+                    AND (last_accessed_at IS NULL OR last_accessed_at < ?3)
+                ",
+            )?
+        };
+        let accessed = at.duration_trunc(LAST_ACCESS_RESOLUTION)?;
+        info_span!("execute").in_scope(|| stmt.execute((namespace, short_form, accessed)))?;
+        Ok(())
+    }
+
+    /// The non-empty hourly click buckets for `short_form` (as stored) that start in `[from, to)`,
+    /// oldest first.
+    #[tracing::instrument(skip(self))]
+    pub fn click_stats(
+        &self,
+        namespace: String,
+        short_form: String,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<ClickBucket>> {
+        let conn = self.lock_conn();
+        let mut stmt = {
+            let _span = info_span!("prepare_statement").entered();
+            conn.prepare(
+                "
+                SELECT hour, count FROM link_clicks
+                WHERE namespace = ?1 AND short_form = ?2 AND hour >= ?3 AND hour < ?4
+                ORDER BY hour
+                ",
+            )?
+        };
+        let buckets: Vec<ClickBucket> = {
+            let _span = info_span!("query_map").entered();
+            stmt.query_map((namespace, short_form, from, to), |row| {
+                Ok(ClickBucket {
+                    hour: row.get(0)?,
+                    count: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?
+        };
+        Ok(buckets)
+    }
+
+    #[tracing::instrument(skip(self, link))]
+    pub fn create_link(&self, namespace: String, link: Link) -> anyhow::Result<WriteOutcome> {
+        self.write_link(namespace, link, WriteMode::Create, None)
+    }
+
+    #[tracing::instrument(skip(self, link))]
+    pub fn update_link(&self, namespace: String, link: Link) -> anyhow::Result<WriteOutcome> {
+        self.write_link(namespace, link, WriteMode::Update, None)
+    }
+
+    /// Like `update_link`, but only if the link currently points at `expected_long_form`, so that
+    /// concurrent editors can't silently overwrite each other.
+    #[tracing::instrument(skip(self, link))]
+    pub fn update_link_if(
+        &self,
+        namespace: String,
+        link: Link,
+        expected_long_form: String,
+    ) -> anyhow::Result<WriteOutcome> {
+        self.write_link(
+            namespace,
+            link,
+            WriteMode::Update,
+            Some(&expected_long_form),
+        )
+    }
+
+    /// Changes just the fields set in `patch`, leaving the rest as stored. Like `update_link`,
+    /// changing how an alias redirects detaches it, and changing how a link redirects carries over
+    /// to its aliases.
+    #[tracing::instrument(skip(self))]
+    pub fn patch_link(
+        &self,
+        namespace: String,
+        short_form: String,
+        patch: LinkPatch,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<WriteOutcome> {
+        anyhow::ensure!(!self.cfg.read_only, "this replica is read-only");
+        let mut conn = self.lock_conn();
+        let tx = conn.transaction()?;
+        let Some(existing) = self.find_link(&tx, &namespace, &short_form, false)? else {
+            return Ok(WriteOutcome::Rejected);
+        };
+        let mut patched = existing.clone();
+        if let Some(long_form) = &patch.long_form {
+            patched.long_form.clone_from(long_form);
+        }
+        if let Some(redirect_status) = patch.redirect_status {
+            patched.redirect_status = redirect_status;
+        }
+        if let Some(redirect_headers) = &patch.redirect_headers {
+            patched.redirect_headers.clone_from(redirect_headers);
+        }
+        if let Some(metadata) = &patch.metadata {
+            patched.metadata.clone_from(metadata);
+        }
+        if let Some(forward_query) = patch.forward_query {
+            patched.forward_query = forward_query;
+        }
+        let redirects_changed = !existing.same_behavior(&patched);
+        if !redirects_changed && existing.metadata == patched.metadata {
+            return Ok(WriteOutcome::Unchanged);
+        }
+        let redirect_headers = if patched.redirect_headers.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&patched.redirect_headers)?)
+        };
+        let metadata = if patched.metadata.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&patched.metadata)?)
+        };
+        // Only the columns the patch names are written, so a concurrent write to any other field
+        // isn't undone by a stale copy of it.
+        let mut sets = vec!["updated_at = ?3"];
+        let mut params: Vec<&dyn rusqlite::ToSql> = vec![&namespace, &existing.short_form, &now];
+        let mut set = |column, value| {
+            params.push(value);
+            sets.push(column);
+        };
+        if patch.long_form.is_some() {
+            set("long_form = ?", &patched.long_form);
+        }
+        if patch.redirect_status.is_some() {
+            set("redirect_status = ?", &patched.redirect_status);
+        }
+        if patch.redirect_headers.is_some() {
+            set("redirect_headers = ?", &redirect_headers);
+        }
+        if patch.metadata.is_some() {
+            set("metadata = ?", &metadata);
+        }
+        if patch.forward_query.is_some() {
+            set("forward_query = ?", &patched.forward_query);
+        }
+        if redirects_changed {
+            sets.push("alias_of = NULL");
+        }
+        let sql = format!(
+            "UPDATE links SET {} WHERE namespace = ?1 AND short_form = ?2",
+            sets.join(", ")
+        );
+        info_span!("execute").in_scope(|| tx.execute(&sql, &params[..]))?;
+        if redirects_changed {
+            let _span = info_span!("update_aliases").entered();
+            tx.execute(
+                "
+                UPDATE links
+                SET long_form = ?3, updated_at = ?4, redirect_status = ?5, redirect_headers = ?6,
+                    forward_query = ?7
+                WHERE namespace = ?1 AND alias_of = ?2
+                ",
+                (
+                    &namespace,
+                    &existing.short_form,
+                    &patched.long_form,
+                    now,
+                    patched.redirect_status,
+                    &redirect_headers,
+                    patched.forward_query,
+                ),
+            )?;
+        }
+        tx.commit()?;
+        self.mark_dirty();
+        Ok(WriteOutcome::Changed)
+    }
+
+    /// Creates each of `links` in one transaction, returning an outcome per link. What's kept when
+    /// some already exist depends on `mode`.
+    #[tracing::instrument(skip(self, links), fields(count = links.len()))]
+    pub fn create_links(
+        &self,
+        namespace: String,
+        links: Vec<Link>,
+        mode: BulkMode,
+    ) -> anyhow::Result<Vec<WriteOutcome>> {
+        anyhow::ensure!(!self.cfg.read_only, "this replica is read-only");
+        let mut conn = self.lock_conn();
+        let tx = conn.transaction()?;
+        let outcomes = links
+            .into_iter()
+            .map(|link| self.write_link_in(&tx, &namespace, link, WriteMode::Create, None))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let rejected = outcomes.iter().any(WriteOutcome::is_rejection);
+        match mode {
+            BulkMode::BestEffort => {}
+            BulkMode::Atomic if !rejected => {}
+            BulkMode::Atomic | BulkMode::DryRun => return Ok(outcomes),
+        }
+        tx.commit()?;
+        if outcomes.contains(&WriteOutcome::Changed) {
+            self.mark_dirty();
+        }
+        Ok(outcomes)
+    }
+
+    /// Stores each of `links` exactly as given (timestamps, alias and deletion included), creating
+    /// or overwriting as needed, so importing the same links twice changes nothing the second time.
+    /// Links the namespace has but `links` doesn't are left alone. `last_accessed_at` is the
+    /// namespace's own, not something to import. All or nothing: if the namespace can't fit the new
+    /// links, none are written.
+    #[tracing::instrument(skip(self, links), fields(count = links.len()))]
+    pub fn import_links(
+        &self,
+        namespace: String,
+        links: Vec<Link>,
+    ) -> anyhow::Result<Vec<WriteOutcome>> {
+        anyhow::ensure!(!self.cfg.read_only, "this replica is read-only");
+        let mut conn = self.lock_conn();
+        let tx = conn.transaction()?;
+        let outcomes = links
+            .into_iter()
+            .map(|link| self.import_link_in(&tx, &namespace, link))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if outcomes.iter().any(WriteOutcome::is_rejection) {
+            return Ok(outcomes);
+        }
+        tx.commit()?;
+        if outcomes.contains(&WriteOutcome::Changed) {
+            self.mark_dirty();
+        }
+        Ok(outcomes)
+    }
+
+    fn import_link_in(
+        &self,
+        tx: &rusqlite::Transaction,
+        namespace: &str,
+        link: Link,
+    ) -> anyhow::Result<WriteOutcome> {
+        let existing = self.find_link(tx, namespace, &link.short_form, true)?;
+        let (sql, short_form) = match existing {
+            Some(existing)
+                if existing.same_behavior(&link)
+                    && existing.metadata == link.metadata
+                    && existing.created_at == link.created_at
+                    && existing.updated_at == link.updated_at
+                    && existing.alias_of == link.alias_of
+                    && existing.deleted_at == link.deleted_at
+                    && existing.enabled == link.enabled =>
+            {
+                return Ok(WriteOutcome::Unchanged)
+            }
+            // Overwrites the row as it was originally stored, like an update would. (Not an upsert,
+            // whose conflict handling would override the REPLACE in the `namespace_changes` triggers.)
+            Some(existing) => (
+                "
+                UPDATE links
+                SET long_form = ?3, created_at = ?4, updated_at = ?5, redirect_status = ?6,
+                    redirect_headers = ?7, metadata = ?8, forward_query = ?9, alias_of = ?10,
+                    deleted_at = ?11, enabled = ?12
+                WHERE namespace = ?1 AND short_form = ?2
+                ",
+                existing.short_form,
+            ),
+            None if link.deleted_at.is_none() && self.namespace_full(tx, namespace)? => {
+                return Ok(WriteOutcome::NamespaceFull)
+            }
+            None if link.deleted_at.is_none() && self.too_many_namespaces(tx, namespace)? => {
+                return Ok(WriteOutcome::TooManyNamespaces)
+            }
+            None => (
+                "
+                INSERT INTO links (
+                    namespace, short_form, long_form, created_at, updated_at, redirect_status,
+                    redirect_headers, metadata, forward_query, alias_of, deleted_at, enabled
+                )
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+                ",
+                link.short_form,
+            ),
+        };
+        let redirect_headers = if link.redirect_headers.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&link.redirect_headers)?)
+        };
+        let metadata = if link.metadata.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&link.metadata)?)
+        };
+        let _span = info_span!("execute").entered();
+        tx.execute(
+            sql,
+            (
+                namespace,
+                short_form,
+                link.long_form,
+                link.created_at,
+                link.updated_at,
+                link.redirect_status,
+                redirect_headers,
+                metadata,
+                link.forward_query,
+                link.alias_of,
+                link.deleted_at,
+                link.enabled,
+            ),
+        )?;
+        Ok(WriteOutcome::Changed)
+    }
+
+    fn write_link(
+        &self,
+        namespace: String,
+        link: Link,
+        mode: WriteMode,
+        expected_long_form: Option<&str>,
+    ) -> anyhow::Result<WriteOutcome> {
+        anyhow::ensure!(!self.cfg.read_only, "this replica is read-only");
+        let mut conn = self.lock_conn();
+        let tx = conn.transaction()?;
+        let outcome = self.write_link_in(&tx, &namespace, link, mode, expected_long_form)?;
+        if outcome == WriteOutcome::Changed {
+            tx.commit()?;
+            self.mark_dirty();
+        }
+        Ok(outcome)
+    }
+
+    fn write_link_in(
+        &self,
+        tx: &rusqlite::Transaction,
+        namespace: &str,
+        link: Link,
+        mode: WriteMode,
+        expected_long_form: Option<&str>,
+    ) -> anyhow::Result<WriteOutcome> {
+        // Matching case-insensitively where configured means a create can't add a second casing and
+        // an update edits the row as it was originally stored.
+        let existing = self.find_link(tx, namespace, &link.short_form, true)?;
+        let (sql, short_form) = match (mode, existing) {
+            (WriteMode::Create, Some(existing)) if existing.deleted_at.is_none() => {
+                return Ok(WriteOutcome::Rejected)
+            }
+            (WriteMode::Update, None) => return Ok(WriteOutcome::Rejected),
+            (WriteMode::Update, Some(existing)) if existing.deleted_at.is_some() => {
+                return Ok(WriteOutcome::Rejected)
+            }
+            // Checked inside the transaction, so nothing can change the link in between.
+            (WriteMode::Update, Some(existing))
+                if expected_long_form.is_some_and(|expected| expected != existing.long_form) =>
+            {
+                return Ok(WriteOutcome::PreconditionFailed)
+            }
+            // Re-submitting what's already stored changes nothing, so there's nothing to back up.
+            (WriteMode::Update, Some(existing))
+                if existing.same_behavior(&link) && existing.metadata == link.metadata =>
+            {
+                return Ok(WriteOutcome::Unchanged)
+            }
+            // Counted inside the transaction, so concurrent creates can't both take the last slot.
+            (WriteMode::Create, _) if self.namespace_full(tx, namespace)? => {
+                return Ok(WriteOutcome::NamespaceFull)
+            }
+            (WriteMode::Create, _) if self.too_many_namespaces(tx, namespace)? => {
+                return Ok(WriteOutcome::TooManyNamespaces)
+            }
+            // Re-creating a deleted link replaces it entirely.
+            (WriteMode::Create, tombstone) => {
+                if let Some(tombstone) = tombstone {
+                    Self::remove_tombstone(tx, namespace, &tombstone.short_form)?;
+                }
+                (
+                    "
+                INSERT INTO links (
+                    namespace, short_form, long_form, created_at, updated_at,
+                    redirect_status, redirect_headers, metadata, forward_query
+                )
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                ",
+                    link.short_form,
+                )
+            }
+            // An edit keeps the link's original created_at and only bumps updated_at. Editing an
+            // alias directly detaches it from its target, making it an ordinary link.
+            (WriteMode::Update, Some(existing)) => (
+                "
+                UPDATE links
+                SET long_form = ?3, updated_at = ?5, redirect_status = ?6, redirect_headers = ?7,
+                    metadata = ?8, forward_query = ?9, alias_of = NULL
+                WHERE namespace = ?1 AND short_form = ?2
+                ",
+                existing.short_form,
+            ),
+        };
+        let redirect_headers = if link.redirect_headers.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&link.redirect_headers)?)
+        };
+        let metadata = if link.metadata.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&link.metadata)?)
+        };
+        let params: [&dyn rusqlite::ToSql; 9] = [
+            &namespace,
+            &short_form,
+            &link.long_form,
+            &link.created_at,
+            &link.updated_at,
+            &link.redirect_status,
+            &redirect_headers,
+            &metadata,
+            &link.forward_query,
+        ];
+        {
+            let mut stmt = {
+                let _span = info_span!("prepare_statement").entered();
+                tx.prepare_cached(sql)?
+            };
+            match info_span!("execute").in_scope(|| stmt.execute(&params[..])) {
+                Ok(_) => {}
+                // `find_link` should already have caught a taken short_form, but the primary key
+                // has the final say. Only this statement is rolled back, not the transaction.
+                Err(err) if is_unique_violation(&err) => return Ok(WriteOutcome::Rejected),
+                Err(err) => return Err(err.into()),
+            }
+            // Aliases follow their target, so they always redirect the same way it does.
+            if let WriteMode::Update = mode {
+                let _span = info_span!("update_aliases").entered();
+                tx.execute(
+                    "
+                    UPDATE links
+                    SET long_form = ?3, updated_at = ?5, redirect_status = ?6, redirect_headers = ?7,
+                        forward_query = ?9
+                    WHERE namespace = ?1 AND alias_of = ?2
+                    ",
+                    // Aliases have their own metadata (?8), so it's left alone.
+                    &params[..],
+                )?;
+            }
+        }
+        Ok(WriteOutcome::Changed)
+    }
+
+    /// Whether `namespace` can't take another link. Deleted links don't count, since they can't be
+    /// seen (and a create over one replaces it).
+    fn namespace_full(
+        &self,
+        tx: &rusqlite::Transaction,
+        namespace: &str,
+    ) -> rusqlite::Result<bool> {
+        let Some(max) = self.cfg.max_links_per_namespace else {
+            return Ok(false);
+        };
+        let _span = info_span!("count_links").entered();
+        let count: usize = tx.query_row(
+            "SELECT COUNT(*) FROM links WHERE namespace = ?1 AND deleted_at IS NULL",
+            [namespace],
+            |row| row.get(0),
+        )?;
+        Ok(count >= max)
+    }
+
+    /// Whether `namespace` would be one more than `max_namespaces` allows. Namespaces whose links
+    /// are all deleted don't count, and can be taken over by new ones.
+    fn too_many_namespaces(
+        &self,
+        tx: &rusqlite::Transaction,
+        namespace: &str,
+    ) -> rusqlite::Result<bool> {
+        let Some(max) = self.cfg.max_namespaces else {
+            return Ok(false);
+        };
+        let _span = info_span!("count_namespaces").entered();
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM links WHERE namespace = ?1 AND deleted_at IS NULL)",
+            [namespace],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Ok(false);
+        }
+        let count: usize = tx.query_row(
+            "SELECT COUNT(DISTINCT namespace) FROM links WHERE deleted_at IS NULL",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(count >= max)
+    }
+
+    /// Adds `alias` as another name for `short_form`. The alias redirects wherever `short_form` does,
+    /// including after `short_form` is edited. Aliasing an alias points at the original target.
+    #[tracing::instrument(skip(self))]
+    pub fn create_alias(
+        &self,
+        namespace: String,
+        short_form: String,
+        alias: String,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<AliasOutcome> {
+        anyhow::ensure!(!self.cfg.read_only, "this replica is read-only");
+        let mut conn = self.lock_conn();
+        let tx = conn.transaction()?;
+        let Some(target) = self.find_link(&tx, &namespace, &short_form, false)? else {
+            return Ok(AliasOutcome::MissingTarget);
+        };
+        match self.find_link(&tx, &namespace, &alias, true)? {
+            Some(existing) if existing.deleted_at.is_none() => {
+                return Ok(AliasOutcome::AlreadyExists)
+            }
+            Some(tombstone) => Self::remove_tombstone(&tx, &namespace, &tombstone.short_form)?,
+            None => {}
+        }
+        if self.namespace_full(&tx, &namespace)? {
+            return Ok(AliasOutcome::NamespaceFull);
+        }
+        let alias_of = target.alias_of.unwrap_or(target.short_form);
+        info_span!("execute").in_scope(|| {
+            tx.execute(
+                "
+                INSERT INTO links (
+                    namespace, short_form, long_form, created_at, updated_at,
+                    redirect_status, redirect_headers, forward_query, alias_of, enabled
+                )
+                SELECT namespace, ?3, long_form, ?4, ?4, redirect_status, redirect_headers,
+                    forward_query, short_form, enabled
+                FROM links WHERE namespace = ?1 AND short_form = ?2
+                ",
+                (&namespace, &alias_of, &alias, now),
+            )
+        })?;
+        let link = self
+            .find_link(&tx, &namespace, &alias, false)?
+            .context("alias vanished after insert")?;
+        tx.commit()?;
+        self.mark_dirty();
+        Ok(AliasOutcome::Created(link))
+    }
+
+    /// Moves a link to `new_short_form`, keeping everything else about it, including its click
+    /// history and its aliases.
+    #[tracing::instrument(skip(self))]
+    pub fn rename_link(
+        &self,
+        namespace: String,
+        short_form: String,
+        new_short_form: String,
+    ) -> anyhow::Result<RenameOutcome> {
+        anyhow::ensure!(!self.cfg.read_only, "this replica is read-only");
+        let mut conn = self.lock_conn();
+        let tx = conn.transaction()?;
+        let Some(link) = self.find_link(&tx, &namespace, &short_form, false)? else {
+            return Ok(RenameOutcome::MissingSource);
+        };
+        match self.find_link(&tx, &namespace, &new_short_form, true)? {
+            // In a case-insensitive namespace, changing only the case finds the link itself.
+            Some(existing) if existing.short_form == link.short_form => {}
+            Some(existing) if existing.deleted_at.is_none() => {
+                return Ok(RenameOutcome::AlreadyExists)
+            }
+            Some(tombstone) => Self::remove_tombstone(&tx, &namespace, &tombstone.short_form)?,
+            None => {}
+        }
+        info_span!("execute").in_scope(|| {
+            // Whatever clicks are left under the new name belonged to a link that's gone now.
+            tx.execute(
+                "DELETE FROM link_clicks WHERE namespace = ?1 AND short_form = ?2",
+                (&namespace, &new_short_form),
+            )?;
+            for table in ["links", "link_clicks"] {
+                tx.execute(
+                    &format!(
+                        "UPDATE {table} SET short_form = ?3 WHERE namespace = ?1 AND short_form = ?2"
+                    ),
+                    (&namespace, &link.short_form, &new_short_form),
+                )?;
+            }
+            tx.execute(
+                "UPDATE links SET alias_of = ?3 WHERE namespace = ?1 AND alias_of = ?2",
+                (&namespace, &link.short_form, &new_short_form),
+            )
+        })?;
+        let link = self
+            .find_link(&tx, &namespace, &new_short_form, false)?
+            .context("link vanished after rename")?;
+        tx.commit()?;
+        self.mark_dirty();
+        Ok(RenameOutcome::Renamed(link))
+    }
+
+    /// Marks the link deleted, so that it stops resolving but can still be recovered with
+    /// `undelete_link`. An alias of it keeps redirecting, since an alias is a link in its own
+    /// right, but becomes an ordinary link: otherwise it would follow whatever link takes the name
+    /// next.
+    #[tracing::instrument(skip(self))]
+    pub fn delete_link(
+        &self,
+        namespace: String,
+        short_form: String,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<WriteOutcome> {
+        anyhow::ensure!(!self.cfg.read_only, "this replica is read-only");
+        let mut conn = self.lock_conn();
+        let tx = conn.transaction()?;
+        let Some(link) = self.find_link(&tx, &namespace, &short_form, false)? else {
+            return Ok(WriteOutcome::Rejected);
+        };
+        info_span!("execute").in_scope(|| {
+            tx.execute(
+                "UPDATE links SET deleted_at = ?3 WHERE namespace = ?1 AND short_form = ?2",
+                (&namespace, &link.short_form, now),
+            )
+        })?;
+        info_span!("detach_aliases").in_scope(|| {
+            tx.execute(
+                "UPDATE links SET alias_of = NULL WHERE namespace = ?1 AND alias_of = ?2",
+                (&namespace, &link.short_form),
+            )
+        })?;
+        tx.commit()?;
+        self.mark_dirty();
+        Ok(WriteOutcome::Changed)
+    }
+
+    /// Marks every one of `short_forms` that exists deleted (detaching their aliases), like
+    /// `delete_link`, in one transaction.
+    /// Returns how many were.
+    #[tracing::instrument(skip(self, short_forms), fields(count = short_forms.len()))]
+    pub fn delete_links(
+        &self,
+        namespace: String,
+        short_forms: &[String],
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<usize> {
+        anyhow::ensure!(!self.cfg.read_only, "this replica is read-only");
+        let collate = if self.cfg.case_insensitive_namespaces.contains(&namespace) {
+            "COLLATE NOCASE"
+        } else {
+            ""
+        };
+        let mut conn = self.lock_conn();
+        let tx = conn.transaction()?;
+        let mut deleted = 0;
+        for chunk in short_forms.chunks(PARAM_CHUNK_SIZE) {
+            let placeholders = vec!["?"; chunk.len()].join(", ");
+            let mut stmt = {
+                let _span = info_span!("prepare_statement").entered();
+                tx.prepare_cached(&format!(
+                    "UPDATE links SET deleted_at = ? WHERE namespace = ? AND short_form {collate} IN ({placeholders}) AND deleted_at IS NULL"
+                ))?
+            };
+            let params = rusqlite::params_from_iter(
+                [&now as &dyn rusqlite::ToSql, &namespace]
+                    .into_iter()
+                    .chain(chunk.iter().map(|sf| sf as &dyn rusqlite::ToSql)),
+            );
+            let _span = info_span!("execute").entered();
+            deleted += stmt.execute(params)?;
+            // Aliases name their target as stored, which the chunk may only match without case.
+            let mut stmt = tx.prepare_cached(&format!(
+                "UPDATE links SET alias_of = NULL WHERE namespace = ? AND alias_of {collate} IN ({placeholders})"
+            ))?;
+            stmt.execute(rusqlite::params_from_iter(
+                [&namespace as &dyn rusqlite::ToSql]
+                    .into_iter()
+                    .chain(chunk.iter().map(|sf| sf as &dyn rusqlite::ToSql)),
+            ))?;
+        }
+        tx.commit()?;
+        if deleted > 0 {
+            self.mark_dirty();
+        }
+        Ok(deleted)
+    }
+
+    /// Permanently removes every link in `namespace`, deleted or not, along with their clicks.
+    /// Returns how many links there were.
+    #[tracing::instrument(skip(self))]
+    pub fn purge_namespace(&self, namespace: String) -> anyhow::Result<usize> {
+        anyhow::ensure!(!self.cfg.read_only, "this replica is read-only");
+        let mut conn = self.lock_conn();
+        let tx = conn.transaction()?;
+        let purged = info_span!("execute").in_scope(|| purge_namespace_in(&tx, &namespace))?;
+        tx.commit()?;
+        if purged > 0 {
+            self.mark_dirty();
+        }
+        Ok(purged)
+    }
+
+    /// Restores a deleted link exactly as it was when it was deleted.
+    #[tracing::instrument(skip(self))]
+    pub fn undelete_link(
+        &self,
+        namespace: String,
+        short_form: String,
+    ) -> anyhow::Result<WriteOutcome> {
+        anyhow::ensure!(!self.cfg.read_only, "this replica is read-only");
+        let conn = self.lock_conn();
+        let link = match self.find_link(&conn, &namespace, &short_form, true)? {
+            Some(link) if link.deleted_at.is_some() => link,
+            _ => return Ok(WriteOutcome::Rejected),
+        };
+        info_span!("execute").in_scope(|| {
+            conn.execute(
+                "UPDATE links SET deleted_at = NULL WHERE namespace = ?1 AND short_form = ?2",
+                (&namespace, &link.short_form),
+            )
+        })?;
+        self.mark_dirty();
+        Ok(WriteOutcome::Changed)
+    }
+
+    /// Takes a live link out of service (or puts it back) without otherwise changing it. Its
+    /// aliases follow. `Rejected` if there's no such link.
+    #[tracing::instrument(skip(self))]
+    pub fn set_enabled(
+        &self,
+        namespace: String,
+        short_form: String,
+        enabled: bool,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<WriteOutcome> {
+        anyhow::ensure!(!self.cfg.read_only, "this replica is read-only");
+        let conn = self.lock_conn();
+        let link = match self.find_link(&conn, &namespace, &short_form, false)? {
+            Some(link) if link.enabled == enabled => return Ok(WriteOutcome::Unchanged),
+            Some(link) => link,
+            None => return Ok(WriteOutcome::Rejected),
+        };
+        // Bumping updated_at changes the ETag, so caches in front of redirects notice.
+        info_span!("execute").in_scope(|| {
+            conn.execute(
+                "
+                UPDATE links SET enabled = ?3, updated_at = ?4
+                WHERE namespace = ?1 AND (short_form = ?2 OR alias_of = ?2)
+                ",
+                (&namespace, &link.short_form, enabled, now),
+            )
+        })?;
+        self.mark_dirty();
+        Ok(WriteOutcome::Changed)
+    }
+
+    /// Makes way for a new link with a deleted link's name.
+    fn remove_tombstone(
+        conn: &rusqlite::Connection,
+        namespace: &str,
+        short_form: &str,
+    ) -> rusqlite::Result<()> {
+        conn.execute(
+            "DELETE FROM links WHERE namespace = ?1 AND short_form = ?2 AND deleted_at IS NOT NULL",
+            (namespace, short_form),
+        )?;
+        Ok(())
+    }
+}
+
+/// Makes `s` match only itself in a LIKE pattern with `ESCAPE '\'`.
+fn escape_like(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BackupSummary {
+    /// Where in the store the backup went.
+    pub path: String,
+    /// In bytes.
+    pub size: u64,
+    /// The mirrors the backup didn't reach, by name, when enough others did to meet the quorum.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed: Vec<String>,
+}
+
+/// Everything since `last_backup_at` is at risk until the next backup succeeds. These are only
+/// tracked in memory, so they start over on a restart.
+#[derive(Clone, Debug, Default, Serialize, ToSchema)]
+pub struct BackupStatus {
+    /// When the last successful backup was staged, so it holds every write from before then.
+    pub last_backup_at: Option<DateTime<Utc>>,
+    /// In bytes.
+    pub last_backup_size: Option<u64>,
+    /// The last write that needs backing up.
+    pub last_dirty_at: Option<DateTime<Utc>>,
+    /// Backups that have failed since the last one succeeded.
+    pub consecutive_failures: u64,
+}
+
+fn is_not_found(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<object_store::Error>(),
+        Some(object_store::Error::NotFound { .. })
+    )
+}
+
+fn is_unique_violation(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(err, _)
+            if err.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY
+                || err.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE
+    )
+}
+
+/// In WAL mode, moves every committed write into the main db file and empties the `-wal` file, so
+/// that it doesn't grow from backup to backup. Does nothing in any other journal mode.
+fn checkpoint_wal(conn: &rusqlite::Connection) -> anyhow::Result<()> {
+    if !in_wal_mode(conn)? {
+        return Ok(());
+    }
+    // Holding the connection lock means none of our own writes are in flight, so this should
+    // only ever be busy if something outside the server has the db open.
+    let (busy, wal_pages, checkpointed): (i64, i64, i64) =
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+    if busy != 0 {
+        warn!(wal_pages, checkpointed, "WAL checkpoint was blocked");
+    } else {
+        info!(checkpointed, "checkpointed WAL");
+    }
+    Ok(())
+}
+
+fn in_wal_mode(conn: &rusqlite::Connection) -> rusqlite::Result<bool> {
+    let journal_mode: String = conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+    Ok(journal_mode.eq_ignore_ascii_case("wal"))
+}
+
+/// A read-only connection to the db at `path`, inside a read transaction. In WAL mode it sees the
+/// db as of when it was opened until it's dropped, while other connections carry on writing.
+fn open_snapshot(path: &std::path::Path) -> anyhow::Result<rusqlite::Connection> {
+    let conn =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    // BEGIN is deferred: it's the first read that actually starts the transaction.
+    conn.execute_batch("BEGIN")?;
+    conn.query_row("SELECT COUNT(*) FROM sqlite_schema", [], |_| Ok(()))?;
+    Ok(conn)
+}
+
+/// Copies `src` (every page, in steps) into a new db at `dest`. Steps are all read within `src`'s
+/// transaction, if it has one open, so the copy is consistent even if the db is written meanwhile.
+fn copy_db(src: &rusqlite::Connection, dest: &std::path::Path) -> anyhow::Result<()> {
+    let mut dest = rusqlite::Connection::open(dest)?;
+    let _span = info_span!("backup").entered();
+    let b = rusqlite::backup::Backup::new(src, &mut dest)?;
+    b.run_to_completion(
+        5,
+        Duration::ZERO,
+        Some(|p| {
+            info!(?p, "backup tick");
+        }),
+    )?;
+    drop(b);
+    // The copy inherits WAL mode from `src`, but a backup is better off as one self-contained file
+    // that needs no `-wal` or `-shm` alongside when it's restored.
+    dest.pragma_update(None, "journal_mode", "delete")?;
+    Ok(())
+}
+
+/// Downloads the backup at `cfg.s3_path` to `cfg.db_path`, returning the version downloaded.
+async fn download(store: &dyn ObjectStore, cfg: &Config) -> anyhow::Result<UpdateVersion> {
+    let (tmp, version) = fetch(store, cfg).await?;
+    tmp.persist(&cfg.db_path)?;
+    Ok(version)
+}
+
+/// `download`, retried as `cfg.restore_retries` allows while the store itself is failing.
+async fn download_with_retries(
+    store: &dyn ObjectStore,
+    cfg: &Config,
+) -> anyhow::Result<UpdateVersion> {
+    let retries = cfg.restore_retries;
+    let started = tokio::time::Instant::now();
+    let mut backoff = retries.initial_backoff;
+    let mut attempt = 1;
+    loop {
+        let err = match download(store, cfg).await {
+            Ok(version) => return Ok(version),
+            Err(err) => err,
+        };
+        let store_failed = matches!(
+            err.downcast_ref::<object_store::Error>(),
+            Some(err) if !matches!(err, object_store::Error::NotFound { .. })
+        );
+        let out_of_time = retries
+            .deadline
+            .is_some_and(|deadline| started.elapsed() + backoff > deadline);
+        if !store_failed || attempt >= retries.max_attempts || out_of_time {
+            return Err(err);
+        }
+        warn!(
+            attempt,
+            ?backoff,
+            ?err,
+            "failed to download backup, retrying"
+        );
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_RESTORE_BACKOFF);
+        attempt += 1;
+    }
+}
+
+/// Downloads the backup at `cfg.s3_path` (decrypted and decompressed as needed) to a temp file
+/// alongside `cfg.db_path`, ready to be renamed into place.
+async fn fetch(
+    store: &dyn ObjectStore,
+    cfg: &Config,
+) -> anyhow::Result<(tempfile::NamedTempFile, UpdateVersion)> {
+    let get_response = store
+        .get(&cfg.s3_path.as_str().into())
+        .await
+        .context("get db from s3")?;
+    info!(?get_response, "found object");
+    let version = UpdateVersion {
+        e_tag: get_response.meta.e_tag.clone(),
+        version: get_response.meta.version.clone(),
+    };
+    let dir = match cfg.db_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    let tmp = tempfile::NamedTempFile::new_in(dir)?;
+    // Streamed chunk by chunk, so that a large db is never held in memory.
+    let mut file = tokio::fs::File::from_std(tmp.reopen()?);
+    let mut chunks = get_response.into_stream();
+    let mut len = 0;
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        len += chunk.len();
+        file.write_all(&chunk).await?;
+    }
+    file.sync_all().await?;
+    info!(len, "downloaded object");
+    let tmp = match &cfg.backup_key {
+        Some(key) => {
+            let key = key.clone();
+            let decrypted = tempfile::NamedTempFile::new_in(dir)?;
+            tokio::task::spawn_blocking(move || {
+                let _span = info_span!("decrypt_backup").entered();
+                encryption::decrypt(&key, tmp.reopen()?, &decrypted)?;
+                decrypted.as_file().sync_all()?;
+                anyhow::Ok(decrypted)
+            })
+            .await??
+        }
+        None => {
+            let mut header = [0; 8];
+            let n = std::io::Read::read(&mut tmp.reopen()?, &mut header)?;
+            anyhow::ensure!(
+                !encryption::is_encrypted(&header[..n]),
+                "backup is encrypted, but no key was given to decrypt it"
+            );
+            tmp
+        }
+    };
+    let tmp = decompress_if_gzipped(tmp, dir).await?;
+    Ok((tmp, version))
+}
+
+// What every gzip stream starts with. A plain db starts with "SQLite format 3\0" instead.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Backups may be plain or gzipped dbs, told apart by their first bytes, so either restores.
+async fn decompress_if_gzipped(
+    tmp: tempfile::NamedTempFile,
+    dir: &std::path::Path,
+) -> anyhow::Result<tempfile::NamedTempFile> {
+    let mut header = [0; GZIP_MAGIC.len()];
+    let n = std::io::Read::read(&mut tmp.reopen()?, &mut header)?;
+    if header[..n] != GZIP_MAGIC {
+        return Ok(tmp);
+    }
+    let mut decompressed = tempfile::NamedTempFile::new_in(dir)?;
+    tokio::task::spawn_blocking(move || {
+        let _span = info_span!("decompress_backup").entered();
+        let mut gz = flate2::read::GzDecoder::new(tmp.reopen()?);
+        let len = std::io::copy(&mut gz, &mut decompressed).context("decompress backup")?;
+        decompressed.as_file().sync_all()?;
+        info!(len, "decompressed backup");
+        anyhow::Ok(decompressed)
+    })
+    .await?
+}
+
+fn connect(cfg: &Config) -> anyhow::Result<rusqlite::Connection> {
+    let conn = if cfg.read_only {
+        rusqlite::Connection::open_with_flags(
+            &cfg.db_path,
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+        )?
+    } else {
+        let conn = rusqlite::Connection::open(&cfg.db_path)?;
+        // So that backups can read their snapshot alongside, rather than in between, writes.
+        conn.pragma_update(None, "journal_mode", "wal")?;
+        conn
+    };
+    let synchronous = match cfg.pragmas.synchronous {
+        Synchronous::Off => "off",
+        Synchronous::Normal => "normal",
+        Synchronous::Full => "full",
+    };
+    conn.pragma_update(None, "synchronous", synchronous)?;
+    if let Some(cache_size) = cfg.pragmas.cache_size {
+        conn.pragma_update(None, "cache_size", cache_size)?;
+    }
+    let temp_store = match cfg.pragmas.temp_store {
+        TempStore::Default => "default",
+        TempStore::File => "file",
+        TempStore::Memory => "memory",
+    };
+    conn.pragma_update(None, "temp_store", temp_store)?;
+    Ok(conn)
+}
+
+/// Copies the staged backup to a mirror, over whatever is there.
+async fn upload_to_mirror(
+    mirror: &BackupMirror,
+    path: &object_store::path::Path,
+    staged: &std::path::Path,
+    size: u64,
+) -> anyhow::Result<PutResult> {
+    let mut file = tokio::fs::File::open(staged).await?;
+    if size <= UPLOAD_PART_SIZE as u64 {
+        let mut content = Vec::with_capacity(size as usize);
+        file.read_to_end(&mut content).await?;
+        return Ok(mirror.store.put(path, PutPayload::from(content)).await?);
+    }
+    put_multipart(mirror.store.as_ref(), path, file).await
+}
+
+/// Streams `file` to `path` in parts, aborting the upload if any part fails.
+async fn put_multipart(
+    store: &dyn ObjectStore,
+    path: &object_store::path::Path,
+    mut file: tokio::fs::File,
+) -> anyhow::Result<PutResult> {
+    let upload = store.put_multipart(path).await?;
+    let mut upload = WriteMultipart::new_with_chunk_size(upload, UPLOAD_PART_SIZE);
+    let mut buf = vec![0; UPLOAD_PART_SIZE];
+    let written: anyhow::Result<()> = async {
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            upload.wait_for_capacity(UPLOAD_CONCURRENCY).await?;
+            upload.write(&buf[..n]);
+        }
+    }
+    .await;
+    if let Err(err) = written {
+        // Otherwise the parts already uploaded linger (and are billed for) indefinitely.
+        if let Err(abort_err) = upload.abort().await {
+            warn!(?abort_err, "failed to abort backup upload");
+        }
+        return Err(err);
+    }
+    Ok(upload.finish().await?)
+}
+
+// Backups up to this size are uploaded in a single request, and larger ones in parts this size
+// (S3's minimum part size is 5 MiB).
+const UPLOAD_PART_SIZE: usize = 8 * 1024 * 1024;
+// How many parts of one backup may be in flight at once.
+const UPLOAD_CONCURRENCY: usize = 4;
+
+/// The rows behind `Persistence::purge_namespace`, shared with s3util's offline purge.
+pub fn purge_namespace_in(tx: &rusqlite::Transaction, namespace: &str) -> rusqlite::Result<usize> {
+    tx.execute("DELETE FROM link_clicks WHERE namespace = ?1", [namespace])?;
+    tx.execute("DELETE FROM links WHERE namespace = ?1", [namespace])
+}
+
+// Keeps each batched query (plus its couple of other parameters) under SQLite's historical limit
+// of 999 bound parameters.
+const PARAM_CHUNK_SIZE: usize = 500;
+
+#[derive(Clone, Copy, Debug)]
+enum WriteMode {
+    /// Insert a new link, refusing to overwrite an existing one.
+    Create,
+    /// Modify an existing link, refusing to create a missing one.
+    Update,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BulkMode {
+    /// Create whichever links can be, even if others already exist.
+    BestEffort,
+    /// Create all of the links or, if any already exist, none of them.
+    Atomic,
+    /// Create nothing, just report what creating each link would do.
+    DryRun,
+}
+
+pub enum AliasOutcome {
+    Created(Link),
+    MissingTarget,
+    /// Something (a link or another alias) already has the alias's name.
+    AlreadyExists,
+    /// The namespace already holds `max_links_per_namespace` links.
+    NamespaceFull,
+}
+
+pub enum RenameOutcome {
+    Renamed(Link),
+    MissingSource,
+    /// Some other link already has the new name.
+    AlreadyExists,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WriteOutcome {
+    Changed,
+    Unchanged,
+    /// The link already exists (for a create), doesn't exist (for an update or delete), or isn't
+    /// deleted (for an undelete).
+    Rejected,
+    /// The link isn't in the state the write expected it to be in.
+    PreconditionFailed,
+    /// The namespace already holds `max_links_per_namespace` links (for a create).
+    NamespaceFull,
+    /// The namespace is new, and there are already `max_namespaces` (for a create).
+    TooManyNamespaces,
+}
+impl WriteOutcome {
+    /// Whether a create was turned away, for whatever reason.
+    pub fn is_rejection(&self) -> bool {
+        matches!(
+            self,
+            Self::Rejected | Self::NamespaceFull | Self::TooManyNamespaces
+        )
+    }
+}
+
+// Deserialized only for imports, so the server-assigned fields all round-trip.
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct Link {
+    pub short_form: String,
+    pub long_form: String,
+    pub created_at: chrono::DateTime<Utc>,
+    pub updated_at: chrono::DateTime<Utc>,
+    /// The status to redirect with, instead of the default 307.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_status: Option<u16>,
+    /// Extra headers (e.g. `Cache-Control`) to send along with the redirect.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub redirect_headers: BTreeMap<String, String>,
+    /// For an alias, the short_form of the link it follows. `None` for an ordinary link.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias_of: Option<String>,
+    /// When the link was (soft-)deleted. Deleted links are only listed on request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// When the link last redirected anyone, to within `LAST_ACCESS_RESOLUTION`. `None` if it
+    /// never has. Set by `record_click`, and ignored by writes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_accessed_at: Option<DateTime<Utc>>,
+    /// Anything else about the link, like its `owner` or `tags`. Not shared with aliases.
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    #[schema(value_type = Object)]
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+    /// Whether redirects append their query string to `long_form`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub forward_query: bool,
+    /// A disabled link is kept (and listed) but doesn't redirect. Aliases follow their target's.
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+fn enabled_by_default() -> bool {
+    true
+}
+const LINK_COLUMNS: &str = "short_form, long_form, created_at, updated_at, redirect_status, \
+                            redirect_headers, alias_of, deleted_at, last_accessed_at, metadata, \
+                            forward_query, enabled";
+/// How precisely `last_accessed_at` is tracked. It's meant for spotting links unused for months,
+/// so a coarse value spares busy links a write on every redirect.
+const LAST_ACCESS_RESOLUTION: TimeDelta = TimeDelta::minutes(1);
+/// Ends the short_form of a wildcard link, which redirects anything under its prefix.
+pub const WILDCARD_SUFFIX: &str = "/*";
+/// Stands in for the matched part of the short_form in a wildcard link's long_form.
+pub const WILDCARD_PLACEHOLDER: &str = "{}";
+impl Link {
+    /// Reads a row selected as `LINK_COLUMNS`.
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        let redirect_headers = match row.get::<_, Option<String>>(5)? {
+            Some(json) => serde_json::from_str(&json).map_err(|err| {
+                rusqlite::Error::FromSqlConversionFailure(5, Type::Text, Box::new(err))
+            })?,
+            None => BTreeMap::new(),
+        };
+        let metadata = match row.get::<_, Option<String>>(9)? {
+            Some(json) => serde_json::from_str(&json).map_err(|err| {
+                rusqlite::Error::FromSqlConversionFailure(9, Type::Text, Box::new(err))
+            })?,
+            None => serde_json::Map::new(),
+        };
+        Ok(Link {
+            short_form: row.get(0)?,
+            long_form: row.get(1)?,
+            created_at: row.get(2)?,
+            updated_at: row.get(3)?,
+            redirect_status: row.get(4)?,
+            redirect_headers,
+            alias_of: row.get(6)?,
+            deleted_at: row.get(7)?,
+            last_accessed_at: row.get(8)?,
+            metadata,
+            forward_query: row.get(10)?,
+            enabled: row.get(11)?,
+        })
+    }
+
+    /// Whether following either link would produce the same response.
+    fn same_behavior(&self, other: &Link) -> bool {
+        self.long_form == other.long_form
+            && self.redirect_status == other.redirect_status
+            && self.redirect_headers == other.redirect_headers
+            && self.forward_query == other.forward_query
+    }
+}
+
+/// The fields `patch_link` changes. `None` leaves a field as it is.
+#[derive(Debug, Default)]
+pub struct LinkPatch {
+    pub long_form: Option<String>,
+    /// `Some(None)` goes back to the default status.
+    pub redirect_status: Option<Option<u16>>,
+    pub redirect_headers: Option<BTreeMap<String, String>>,
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    pub forward_query: Option<bool>,
+}
+
+/// Narrows `list_links`. The default is every live link.
+#[derive(Debug, Default)]
+pub struct LinkFilter {
+    /// Also include deleted links, which is for recovering them.
+    pub include_deleted: bool,
+    /// Only links whose metadata `tags` include this.
+    pub tag: Option<String>,
+    /// Only links whose metadata `owner` is this.
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct NamespaceSummary {
+    pub namespace: String,
+    /// How many live (not deleted) links it has.
+    pub links: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ClickBucket {
+    /// The start of the hour, in UTC.
+    pub hour: DateTime<Utc>,
+    pub count: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::memory::InMemory;
+
+    use super::*;
+
+    fn test_config(db_path: PathBuf) -> Config {
+        Config {
+            db_path,
+            backup_staging_dir: None,
+            s3_path: "backup.sqlite".to_owned(),
+            read_only: false,
+            case_insensitive_namespaces: HashSet::new(),
+            auto_migrate: false,
+            backup_key: None,
+            max_links_per_namespace: None,
+            max_namespaces: None,
+            link_cache_size: None,
+            pragmas: Pragmas::default(),
+            backup_quorum: None,
+            restore_retries: RestoreRetries::default(),
+        }
+    }
+
+    #[test]
+    fn survives_a_panic_holding_the_lock() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        crate::schema::ensure_schema(&mut conn).unwrap();
+        let cfg = test_config(PathBuf::new());
+        let persistence = Persistence::new(cfg, conn, Arc::new(InMemory::new()));
+        std::thread::scope(|s| {
+            let result = s
+                .spawn(|| {
+                    let _conn = persistence.lock_conn();
+                    panic!("handler bug");
+                })
+                .join();
+            assert!(result.is_err());
+        });
+        assert!(persistence.conn.is_poisoned());
+        assert!(persistence
+            .list_links("ns".to_owned(), LinkFilter::default())
+            .unwrap()
+            .is_empty());
+        assert!(!persistence.conn.is_poisoned());
+    }
+
+    #[test]
+    fn duplicate_inserts_are_unique_violations() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        crate::schema::ensure_schema(&mut conn).unwrap();
+        let insert = "
+            INSERT INTO links (namespace, short_form, long_form, created_at, updated_at)
+            VALUES ('ns', 'foo', 'https://example.com', '', '')
+        ";
+        conn.execute(insert, []).unwrap();
+        let err = conn.execute(insert, []).unwrap_err();
+        assert!(is_unique_violation(&err), "{err:?}");
+
+        let err = conn
+            .execute("INSERT INTO nonexistent VALUES (1)", [])
+            .unwrap_err();
+        assert!(!is_unique_violation(&err), "{err:?}");
+    }
+
+    #[test]
+    fn connections_get_the_configured_pragmas() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = Config {
+            pragmas: Pragmas {
+                synchronous: Synchronous::Normal,
+                cache_size: Some(-8000),
+                temp_store: TempStore::Memory,
+            },
+            ..test_config(dir.path().join("db.sqlite"))
+        };
+        let conn = connect(&cfg).unwrap();
+        let pragma = |name: &str| -> i64 {
+            conn.pragma_query_value(None, name, |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(pragma("synchronous"), 1);
+        assert_eq!(pragma("cache_size"), -8000);
+        assert_eq!(pragma("temp_store"), 2);
+        assert!(in_wal_mode(&conn).unwrap());
+    }
+
+    #[test]
+    fn backups_checkpoint_the_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("db.sqlite");
+        let mut conn = rusqlite::Connection::open(&db_path).unwrap();
+        conn.pragma_update(None, "journal_mode", "wal").unwrap();
+        crate::schema::ensure_schema(&mut conn).unwrap();
+        let cfg = test_config(db_path);
+        let persistence = Persistence::new(cfg, conn, Arc::new(InMemory::new()));
+        let wal_len = || {
+            std::fs::metadata(dir.path().join("db.sqlite-wal"))
+                .unwrap()
+                .len()
+        };
+        assert!(wal_len() > 0);
+
+        persistence.stage_backup().unwrap();
+        assert_eq!(wal_len(), 0);
+    }
+
+    #[test]
+    fn backup_snapshots_dont_block_or_see_later_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("db.sqlite");
+        let mut conn = rusqlite::Connection::open(&db_path).unwrap();
+        conn.pragma_update(None, "journal_mode", "wal").unwrap();
+        crate::schema::ensure_schema(&mut conn).unwrap();
+        let insert = |conn: &rusqlite::Connection, short_form: &str| {
+            conn.execute(
+                "INSERT INTO links (namespace, short_form, long_form, created_at, updated_at)
+                 VALUES ('ns', ?1, 'https://example.com', ?2, ?2)",
+                (short_form, Utc::now()),
+            )
+        };
+        insert(&conn, "before").unwrap();
+
+        let snapshot = open_snapshot(&db_path).unwrap();
+        // Not blocked by the snapshot's open read transaction.
+        insert(&conn, "after").unwrap();
+        let staged = dir.path().join("staged.sqlite");
+        copy_db(&snapshot, &staged).unwrap();
+
+        let staged = rusqlite::Connection::open(&staged).unwrap();
+        let short_forms: Vec<String> = staged
+            .prepare("SELECT short_form FROM links")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(short_forms, ["before"]);
+        let journal_mode: String = staged
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "delete");
+    }
+}
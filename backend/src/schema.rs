@@ -0,0 +1,199 @@
+use tracing::info;
+
+const DDL_LINKS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS links (
+        namespace TEXT NOT NULL,
+        short_form TEXT NOT NULL,
+        long_form TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        PRIMARY KEY (namespace, short_form)
+    )
+";
+// Backs reverse_lookup, which filters on (namespace, long_form) rather than the primary key.
+const DDL_LINKS_LONG_FORM_INDEX: &str = "
+    CREATE INDEX IF NOT EXISTS idx_links_namespace_long_form ON links(namespace, long_form)
+";
+// Existing rows have never been edited, so they were last updated when they were created.
+const DDL_LINKS_UPDATED_AT: &str = "
+    ALTER TABLE links ADD COLUMN updated_at TEXT;
+    UPDATE links SET updated_at = created_at;
+";
+// Backs lookups in case-insensitive namespaces, which compare short_form with NOCASE.
+const DDL_LINKS_SHORT_FORM_NOCASE_INDEX: &str = "
+    CREATE INDEX IF NOT EXISTS idx_links_namespace_short_form_nocase
+    ON links(namespace, short_form COLLATE NOCASE)
+";
+// Redirects per link per UTC hour. `hour` is the start of the bucket, truncated to the hour.
+const DDL_LINK_CLICKS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS link_clicks (
+        namespace TEXT NOT NULL,
+        short_form TEXT NOT NULL,
+        hour TEXT NOT NULL,
+        count INTEGER NOT NULL,
+        PRIMARY KEY (namespace, short_form, hour)
+    )
+";
+// Per-link overrides for how redirects are issued. `redirect_headers` is a JSON object of header
+// names to values; NULL in either column means the default behavior.
+const DDL_LINKS_REDIRECT_OPTIONS: &str = "
+    ALTER TABLE links ADD COLUMN redirect_status INTEGER
+        CHECK (redirect_status IN (301, 302, 307, 308));
+    ALTER TABLE links ADD COLUMN redirect_headers TEXT;
+";
+// An alias is a row that mirrors another link in the same namespace, named by `alias_of`.
+const DDL_LINKS_ALIAS_OF: &str = "
+    ALTER TABLE links ADD COLUMN alias_of TEXT;
+    CREATE INDEX IF NOT EXISTS idx_links_namespace_alias_of ON links(namespace, alias_of);
+";
+// Deleting a link only tombstones it, so it can be recovered.
+const DDL_LINKS_DELETED_AT: &str = "
+    ALTER TABLE links ADD COLUMN deleted_at TEXT;
+";
+// When the link last redirected anyone, at `LAST_ACCESS_RESOLUTION`. NULL if it never has.
+const DDL_LINKS_LAST_ACCESSED_AT: &str = "
+    ALTER TABLE links ADD COLUMN last_accessed_at TEXT;
+";
+// Free-form JSON object (owner, tags, ...) for organizing links. NULL means none.
+const DDL_LINKS_METADATA: &str = "
+    ALTER TABLE links ADD COLUMN metadata TEXT CHECK (metadata IS NULL OR json_valid(metadata));
+";
+// Whether redirects append the incoming query string to the destination. Off unless opted into,
+// since the query might not be meant for the destination.
+const DDL_LINKS_FORWARD_QUERY: &str = "
+    ALTER TABLE links ADD COLUMN forward_query INTEGER NOT NULL DEFAULT 0;
+";
+// When each namespace's links last changed in any way, for Last-Modified on listings. Triggers
+// keep it current, since renames and undeletes don't move any of a link's own timestamps.
+// Existing namespaces are backfilled with their newest timestamp.
+const DDL_NAMESPACE_CHANGES: &str = "
+    CREATE TABLE IF NOT EXISTS namespace_changes (
+        namespace TEXT PRIMARY KEY,
+        changed_at TEXT NOT NULL
+    );
+    INSERT INTO namespace_changes (namespace, changed_at)
+    SELECT namespace, MAX(MAX(updated_at, COALESCE(deleted_at, ''), COALESCE(last_accessed_at, '')))
+    FROM links GROUP BY namespace;
+    CREATE TRIGGER IF NOT EXISTS links_inserted AFTER INSERT ON links BEGIN
+        REPLACE INTO namespace_changes (namespace, changed_at)
+        VALUES (NEW.namespace, strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'));
+    END;
+    CREATE TRIGGER IF NOT EXISTS links_updated AFTER UPDATE ON links BEGIN
+        REPLACE INTO namespace_changes (namespace, changed_at)
+        VALUES (NEW.namespace, strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'));
+    END;
+    CREATE TRIGGER IF NOT EXISTS links_deleted AFTER DELETE ON links BEGIN
+        REPLACE INTO namespace_changes (namespace, changed_at)
+        VALUES (OLD.namespace, strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'));
+    END;
+";
+
+// Disabled links stay listed but don't redirect, until they're enabled again.
+const DDL_LINKS_ENABLED: &str = "
+    ALTER TABLE links ADD COLUMN enabled INTEGER NOT NULL DEFAULT 1;
+";
+// Backs finding a short_form across every namespace.
+const DDL_LINKS_SHORT_FORM_INDEX: &str = "
+    CREATE INDEX IF NOT EXISTS idx_links_short_form ON links(short_form);
+";
+// Redirects only bump `last_accessed_at`, which shouldn't count as a change to clients that just
+// want the links themselves, so `links_updated` now leaves that column out (and has to list every
+// other one). Listings show it, though, so accesses are tracked on their own.
+const DDL_NAMESPACE_ACCESSES: &str = "
+    DROP TRIGGER IF EXISTS links_updated;
+    CREATE TRIGGER links_updated AFTER UPDATE OF
+        namespace, short_form, long_form, created_at, updated_at, redirect_status,
+        redirect_headers, alias_of, deleted_at, metadata, forward_query, enabled
+    ON links BEGIN
+        REPLACE INTO namespace_changes (namespace, changed_at)
+        VALUES (NEW.namespace, strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'));
+    END;
+    CREATE TABLE IF NOT EXISTS namespace_accesses (
+        namespace TEXT PRIMARY KEY,
+        accessed_at TEXT NOT NULL
+    );
+    INSERT INTO namespace_accesses (namespace, accessed_at)
+    SELECT namespace, MAX(last_accessed_at) FROM links
+    WHERE last_accessed_at IS NOT NULL GROUP BY namespace;
+    CREATE TRIGGER IF NOT EXISTS links_accessed AFTER UPDATE OF last_accessed_at ON links BEGIN
+        REPLACE INTO namespace_accesses (namespace, accessed_at)
+        VALUES (NEW.namespace, strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'));
+    END;
+";
+
+// Applied in order, each exactly once. `PRAGMA user_version` records how many have run, so
+// only append to this list: never edit or reorder an entry that has shipped.
+const MIGRATIONS: &[&str] = &[
+    DDL_LINKS_TABLE,
+    DDL_LINKS_LONG_FORM_INDEX,
+    DDL_LINKS_UPDATED_AT,
+    DDL_LINKS_SHORT_FORM_NOCASE_INDEX,
+    DDL_LINK_CLICKS_TABLE,
+    DDL_LINKS_REDIRECT_OPTIONS,
+    DDL_LINKS_ALIAS_OF,
+    DDL_LINKS_DELETED_AT,
+    DDL_LINKS_LAST_ACCESSED_AT,
+    DDL_LINKS_METADATA,
+    DDL_LINKS_FORWARD_QUERY,
+    DDL_NAMESPACE_CHANGES,
+    DDL_LINKS_ENABLED,
+    DDL_LINKS_SHORT_FORM_INDEX,
+    DDL_NAMESPACE_ACCESSES,
+];
+
+pub fn ensure_schema(conn: &mut rusqlite::Connection) -> anyhow::Result<()> {
+    let tx = conn.transaction()?;
+    let version: usize = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (idx, ddl) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!(version = idx + 1, "applying migration");
+        tx.execute_batch(ddl)?;
+        tx.pragma_update(None, "user_version", idx + 1)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ensure_schema_is_idempotent() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        ensure_schema(&mut conn).unwrap();
+        ensure_schema(&mut conn).unwrap();
+        let version: usize = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn reverse_lookup_uses_long_form_index() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        ensure_schema(&mut conn).unwrap();
+        let tx = conn.transaction().unwrap();
+        for i in 0..1000 {
+            tx.execute(
+                "INSERT INTO links (namespace, short_form, long_form, created_at, updated_at)
+                 VALUES ('ns', ?1, ?2, '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z')",
+                (format!("s{i}"), format!("https://example.com/{i}")),
+            )
+            .unwrap();
+        }
+        tx.commit().unwrap();
+        conn.execute("ANALYZE", []).unwrap();
+
+        let plan: Vec<String> = conn
+            .prepare("EXPLAIN QUERY PLAN SELECT * FROM links WHERE namespace = ? AND long_form = ?")
+            .unwrap()
+            .query_map(["ns", "https://example.com/7"], |row| row.get(3))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert!(
+            plan.iter()
+                .any(|step| step.contains("USING INDEX idx_links_namespace_long_form")),
+            "{plan:?}"
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use rand::Rng;
+
+/// Words that read like part of the app, or could collide with its routes.
+pub const RESERVED_WORDS: &[&str] = &[
+    "admin", "api", "app", "auth", "count", "delete", "export", "health", "home", "import",
+    "login", "map", "random", "resolve", "search", "static", "stats", "validate", "www",
+];
+/// Words nobody wants printed on a flyer.
+pub const OFFENSIVE_WORDS: &[&str] = &[
+    "anal", "anus", "arse", "ass", "bitch", "boob", "butt", "cock", "crap", "cum", "cunt", "dick",
+    "dildo", "fag", "fuck", "jizz", "kkk", "nazi", "nig", "penis", "piss", "porn", "poop", "pussy",
+    "rape", "sex", "shit", "slut", "tit", "twat", "wank", "whore",
+];
+
+const ALPHABET: &str = "abcdefghijklmnopqrstuvwxyz0123456789";
+/// `ALPHABET` without the characters easily mistaken for others when read aloud or off a flyer:
+/// `0`/`o`, `1`/`l`/`i`.
+pub const UNAMBIGUOUS_ALPHABET: &str = "abcdefghjkmnpqrstuvwxyz23456789";
+const LENGTH: usize = 6;
+/// Shorter slugs run out (and collide) too quickly to be worth generating.
+pub const MIN_LENGTH: usize = 3;
+/// Longer slugs defeat the point of a short link.
+pub const MAX_LENGTH: usize = 32;
+// Only a blocklist that matches nearly everything should ever get close to this.
+const MAX_ATTEMPTS: usize = 100;
+
+/// Makes up short_forms for links created without one.
+#[derive(Debug)]
+pub struct SlugGenerator {
+    /// Lowercase. A candidate containing any of these anywhere is thrown away.
+    blocklist: Vec<String>,
+    /// Distinct characters, each equally likely at every position.
+    alphabet: Vec<char>,
+    length: usize,
+}
+
+impl Default for SlugGenerator {
+    fn default() -> Self {
+        Self::new(
+            RESERVED_WORDS
+                .iter()
+                .chain(OFFENSIVE_WORDS)
+                .map(|word| word.to_string()),
+        )
+    }
+}
+
+impl SlugGenerator {
+    pub fn new(blocklist: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            blocklist: blocklist
+                .into_iter()
+                .map(|word| word.to_lowercase())
+                .collect(),
+            alphabet: ALPHABET.chars().collect(),
+            length: LENGTH,
+        }
+    }
+
+    /// Draws slugs from `alphabet` instead: either its characters, or the name of a preset
+    /// (`unambiguous`, for `UNAMBIGUOUS_ALPHABET`). Characters have to be safe in a URL path
+    /// unescaped, so letters, digits, `-` and `_`.
+    pub fn with_alphabet(mut self, alphabet: &str) -> anyhow::Result<Self> {
+        let alphabet = match alphabet {
+            "unambiguous" => UNAMBIGUOUS_ALPHABET,
+            chars => chars,
+        };
+        if let Some(c) = alphabet
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_'))
+        {
+            bail!("slug alphabet can't contain {c:?}; use letters, digits, '-' and '_'");
+        }
+        let mut chars: Vec<char> = alphabet.chars().collect();
+        chars.sort_unstable();
+        chars.dedup();
+        if chars.len() < 2 {
+            bail!("slug alphabet needs at least two distinct characters");
+        }
+        self.alphabet = chars;
+        Ok(self)
+    }
+
+    /// Makes slugs `length` characters long instead, between `MIN_LENGTH` and `MAX_LENGTH`.
+    pub fn with_length(mut self, length: usize) -> anyhow::Result<Self> {
+        if !(MIN_LENGTH..=MAX_LENGTH).contains(&length) {
+            bail!("slug length must be between {MIN_LENGTH} and {MAX_LENGTH}, not {length}");
+        }
+        self.length = length;
+        Ok(self)
+    }
+
+    /// Reads a blocklist with one word per line, in place of the built-in words. Blank lines and
+    /// lines starting with `#` are skipped.
+    pub fn from_blocklist_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("read slug blocklist {}", path.display()))?;
+        Ok(Self::new(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_owned),
+        ))
+    }
+
+    pub fn generate(&self) -> anyhow::Result<String> {
+        let mut rng = rand::thread_rng();
+        for _ in 0..MAX_ATTEMPTS {
+            let candidate: String = (0..self.length)
+                .map(|_| self.alphabet[rng.gen_range(0..self.alphabet.len())])
+                .collect();
+            if !self.is_blocked(&candidate) {
+                return Ok(candidate);
+            }
+        }
+        bail!("no short_form outside the blocklist after {MAX_ATTEMPTS} attempts")
+    }
+
+    fn is_blocked(&self, candidate: &str) -> bool {
+        let candidate = candidate.to_lowercase();
+        self.blocklist
+            .iter()
+            .any(|word| candidate.contains(word.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocked_words_match_anywhere() {
+        let slugs = SlugGenerator::new(["api".to_owned(), "Search".to_owned()]);
+        assert!(slugs.is_blocked("api"));
+        assert!(slugs.is_blocked("x7apiq"));
+        assert!(slugs.is_blocked("SEARCH"));
+        assert!(!slugs.is_blocked("a1p2i3"));
+    }
+
+    #[test]
+    fn generated_slugs_avoid_the_blocklist() {
+        let slugs = SlugGenerator::new(["a".to_owned()]);
+        for _ in 0..20 {
+            let slug = slugs.generate().unwrap();
+            assert_eq!(slug.len(), LENGTH);
+            assert!(!slug.contains('a'), "{slug}");
+        }
+        let slugs = SlugGenerator::new(('a'..='z').chain('0'..='9').map(String::from));
+        assert!(slugs.generate().is_err());
+    }
+
+    #[test]
+    fn alphabet_and_length_are_configurable() {
+        let slugs = SlugGenerator::new([])
+            .with_alphabet("unambiguous")
+            .unwrap()
+            .with_length(4)
+            .unwrap();
+        for _ in 0..20 {
+            let slug = slugs.generate().unwrap();
+            assert_eq!(slug.len(), 4);
+            assert!(
+                slug.chars().all(|c| UNAMBIGUOUS_ALPHABET.contains(c)),
+                "{slug}"
+            );
+        }
+        let slugs = SlugGenerator::new([]).with_alphabet("AB-AB").unwrap();
+        assert_eq!(slugs.alphabet, ['-', 'A', 'B']);
+
+        for alphabet in ["", "aaaa", "ab/c", "héllo"] {
+            assert!(
+                SlugGenerator::default().with_alphabet(alphabet).is_err(),
+                "{alphabet:?}"
+            );
+        }
+        for length in [0, MIN_LENGTH - 1, MAX_LENGTH + 1] {
+            assert!(
+                SlugGenerator::default().with_length(length).is_err(),
+                "{length}"
+            );
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use object_store::{
+    aws::{AmazonS3Builder, S3ConditionalPut},
+    azure::MicrosoftAzureBuilder,
+    gcp::GoogleCloudStorageBuilder,
+    local::LocalFileSystem,
+    ClientOptions, ObjectStore, RetryConfig,
+};
+
+use crate::persistence::BackupMirror;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum StoreBackend {
+    S3,
+    Gcs,
+    Azure,
+    Local,
+}
+
+/// Where backups live. Cloud credentials are read from the environment by each backend's builder:
+/// - s3: AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY
+/// - gcs: GOOGLE_SERVICE_ACCOUNT (a path to a service account key)
+/// - azure: AZURE_STORAGE_ACCOUNT_NAME, AZURE_STORAGE_ACCOUNT_KEY
+#[derive(clap::Args, Clone, Debug)]
+pub struct StoreArgs {
+    #[arg(long, env = "FLYLINKS_STORE_BACKEND", visible_alias = "backup-backend", value_enum, default_value_t = StoreBackend::S3)]
+    pub store_backend: StoreBackend,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_S3_BUCKET",
+        visible_alias = "bucket",
+        help = "The bucket (or Azure container) holding backups"
+    )]
+    pub s3_bucket: Option<String>,
+
+    #[arg(long, env = "FLYLINKS_S3_REGION", help = "Only used by the s3 backend")]
+    pub s3_region: Option<String>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_STORE_DIR",
+        visible_alias = "backup-dir",
+        help = "The directory holding backups for the local backend, handy for offline development"
+    )]
+    pub store_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_S3_CONNECT_TIMEOUT_SECS",
+        visible_alias = "s3-connect-timeout",
+        default_value_t = 5,
+        help = "Give up connecting to the cloud backend after this long"
+    )]
+    pub s3_connect_timeout_secs: u64,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_S3_REQUEST_TIMEOUT_SECS",
+        visible_alias = "s3-request-timeout",
+        default_value_t = 30,
+        help = "Give up on any one request to the cloud backend (or one attempt at it) after this long"
+    )]
+    pub s3_request_timeout_secs: u64,
+
+    #[arg(
+        long,
+        env = "FLYLINKS_S3_MAX_RETRIES",
+        default_value_t = 10,
+        help = "Retry failed requests to the cloud backend this many times, backing off between attempts"
+    )]
+    pub s3_max_retries: usize,
+
+    #[arg(
+        long = "backup-mirror",
+        env = "FLYLINKS_BACKUP_MIRRORS",
+        value_delimiter = ',',
+        help = "Also copy every backup to this bucket (a directory for the local backend), using the same backend and credentials; for s3, BUCKET:REGION puts it in another region (repeatable)"
+    )]
+    pub backup_mirrors: Vec<String>,
+}
+
+impl StoreArgs {
+    pub fn build(&self) -> anyhow::Result<Arc<dyn ObjectStore>> {
+        let store: Arc<dyn ObjectStore> = match self.store_backend {
+            StoreBackend::S3 => Arc::new(
+                AmazonS3Builder::from_env()
+                    .with_region(
+                        self.s3_region
+                            .as_deref()
+                            .context("--s3-region is required")?,
+                    )
+                    .with_bucket_name(self.bucket()?)
+                    .with_client_options(self.client_options())
+                    .with_retry(self.retry_config())
+                    // Lets backups refuse to overwrite one another writer made; see `backup_to_s3`.
+                    .with_conditional_put(S3ConditionalPut::ETagMatch)
+                    .build()
+                    .context("init s3")?,
+            ),
+            StoreBackend::Gcs => Arc::new(
+                GoogleCloudStorageBuilder::from_env()
+                    .with_bucket_name(self.bucket()?)
+                    .with_client_options(self.client_options())
+                    .with_retry(self.retry_config())
+                    .build()
+                    .context("init gcs")?,
+            ),
+            StoreBackend::Azure => Arc::new(
+                MicrosoftAzureBuilder::from_env()
+                    .with_container_name(self.bucket()?)
+                    .with_client_options(self.client_options())
+                    .with_retry(self.retry_config())
+                    .build()
+                    .context("init azure")?,
+            ),
+            StoreBackend::Local => {
+                let dir = self.store_dir.as_ref().context("--store-dir is required")?;
+                std::fs::create_dir_all(dir)?;
+                Arc::new(LocalFileSystem::new_with_prefix(dir).context("init local store")?)
+            }
+        };
+        Ok(store)
+    }
+
+    /// One store per `--backup-mirror`, configured like the main one but for the bucket (and
+    /// region, or directory) in its place.
+    pub fn build_mirrors(&self) -> anyhow::Result<Vec<BackupMirror>> {
+        self.backup_mirrors
+            .iter()
+            .map(|mirror| {
+                let mut args = self.clone();
+                match self.store_backend {
+                    StoreBackend::S3 => {
+                        let (bucket, region) = match mirror.split_once(':') {
+                            Some((bucket, region)) => (bucket, Some(region.to_owned())),
+                            None => (mirror.as_str(), None),
+                        };
+                        args.s3_bucket = Some(bucket.to_owned());
+                        args.s3_region = region.or(args.s3_region);
+                    }
+                    StoreBackend::Gcs | StoreBackend::Azure => {
+                        args.s3_bucket = Some(mirror.clone())
+                    }
+                    StoreBackend::Local => args.store_dir = Some(mirror.into()),
+                }
+                let store = args
+                    .build()
+                    .with_context(|| format!("init backup mirror {mirror}"))?;
+                Ok(BackupMirror {
+                    name: mirror.clone(),
+                    store,
+                })
+            })
+            .collect()
+    }
+
+    fn client_options(&self) -> ClientOptions {
+        ClientOptions::new()
+            .with_connect_timeout(Duration::from_secs(self.s3_connect_timeout_secs))
+            .with_timeout(Duration::from_secs(self.s3_request_timeout_secs))
+    }
+
+    fn retry_config(&self) -> RetryConfig {
+        RetryConfig {
+            max_retries: self.s3_max_retries,
+            ..RetryConfig::default()
+        }
+    }
+
+    fn bucket(&self) -> anyhow::Result<&str> {
+        self.s3_bucket.as_deref().context("--s3-bucket is required")
+    }
+}
@@ -88,14 +88,24 @@ async function handleSetKey(key) {
     chrome.tabs.query({ active: true, currentWindow: true }, async (tabs) => {
       const url = tabs[0].url;
       console.log(`setting key ${key} = ${url}`);
-      const resp = await fetch(BASE + '/v1/links/' + namespace, {
+      let resp = await fetch(BASE + '/v1/links/' + namespace, {
         method: 'POST',
         headers: {
           "Content-Type": "application/json",
         },
         body: JSON.stringify({short_form: key, long_form: url}),
       });
-      if (resp.status === 200) {
+      if (resp.status === 409) {
+        // The key already exists, so this is an edit rather than a create.
+        resp = await fetch(BASE + '/v1/links/' + namespace + '/' + key, {
+          method: 'PUT',
+          headers: {
+            "Content-Type": "application/json",
+          },
+          body: JSON.stringify({long_form: url}),
+        });
+      }
+      if (resp.ok) {
         resolve();
       } else {
         reject();